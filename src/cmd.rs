@@ -1,16 +1,825 @@
 //! Module for command-line parsing.
 
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use regex::Regex;
+use structopt::clap::Shell;
+
 /// The basic command set.
+// Only one command is ever parsed, so the size of the shuffle options doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(StructOpt, Debug)]
 #[structopt(rename_all = "kebab-case")]
 pub enum BaseCmd {
     /// Shuffle the user's entire library into a playlist.
-    ShuffleLibrary,
+    ShuffleLibrary(ShuffleLibraryOpts),
+    /// Queue the tracks of random albums from the library, without creating a playlist.
+    QueueNextAlbums {
+        /// How many albums to queue.
+        #[structopt(value_name = "N")]
+        count: usize,
+        /// Seed the choice of albums, so running again with the same seed and library queues the
+        /// same albums.
+        #[structopt(long, value_name = "N")]
+        seed: Option<u64>,
+        /// The name or ID of the device to queue on. By default, the active device is used.
+        #[structopt(long, value_name = "NAME")]
+        device: Option<String>,
+        /// Track groups are chosen with the same options as `shuffle-library`.
+        #[structopt(flatten)]
+        opts: GroupOpts,
+    },
+    /// List the playlists in the user's library and whether `shuffle-library` would use them.
+    ListPlaylists {
+        /// Sort by `name` or by `tracks` (most first) instead of library order.
+        #[structopt(long)]
+        sort: Option<PlaylistSort>,
+        /// Playlists are excluded with the same options as `shuffle-library`.
+        #[structopt(flatten)]
+        opts: SourceOpts,
+    },
+    /// Write every playlist in the user's library to a JSON file in a directory.
+    BackupAll {
+        /// Directory to write the backup files to. It will be created if it does not exist.
+        #[structopt(long, parse(from_os_str))]
+        dir: PathBuf,
+        /// Number of playlists to back up in parallel.
+        #[structopt(long, default_value = "1")]
+        jobs: usize,
+        /// Extra track details to include, separated by commas: `name`, `artists`, `album`,
+        /// `duration-ms`, `isrc`, `popularity`, `explicit` and `track-number`.
+        #[structopt(long, use_delimiter = true)]
+        fields: Vec<TrackField>,
+    },
+    /// Remove tracks from the shuffle which are no longer in the library or can't be played.
+    PruneShuffle {
+        /// The name of the playlist to prune.
+        #[structopt(long, value_name = "NAME", default_value = "Shuffle")]
+        playlist: String,
+        /// Count tracks on saved albums as still being in the library.
+        #[structopt(long)]
+        include_saved_albums: bool,
+    },
+    /// Replace the contents of a playlist with the tracks in a file written by `--export` or
+    /// `backup-all`, such as to undo a shuffle.
+    #[structopt(alias = "import-playlist")]
+    Restore {
+        /// An M3U or JSON file of tracks.
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// The name of the playlist to restore into. It is created if it does not exist.
+        #[structopt(long, value_name = "NAME", default_value = "Shuffle")]
+        playlist: String,
+    },
+    /// Check that authentication works and the Spotify API is reachable.
+    Health {
+        /// Output format: `text` or `json`.
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+    },
+    /// Print a shell completion script to stdout.
+    Completions {
+        /// The shell to generate completions for.
+        #[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+        shell: Shell,
+    },
+}
+
+/// Write the completion script for `shell` covering every command and option to `out`.
+pub fn write_completions<W: Write>(shell: Shell, out: &mut W) {
+    <BaseOpts as structopt::StructOpt>::clap().gen_completions_to("spotctl", shell, out);
+}
+
+/// How a command should print its results.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("Unknown output format \"{}\" (expected \"text\" or \"json\")", s)),
+        }
+    }
+}
+
+/// A track detail which can be included in exported track listings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrackField {
+    Name,
+    Artists,
+    Album,
+    DurationMs,
+    Isrc,
+    Popularity,
+    Explicit,
+    TrackNumber,
+}
+
+impl TrackField {
+    /// The name of the field, as given on the command line and written in exports.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TrackField::Name => "name",
+            TrackField::Artists => "artists",
+            TrackField::Album => "album",
+            TrackField::DurationMs => "duration-ms",
+            TrackField::Isrc => "isrc",
+            TrackField::Popularity => "popularity",
+            TrackField::Explicit => "explicit",
+            TrackField::TrackNumber => "track-number",
+        }
+    }
+}
+
+impl FromStr for TrackField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(TrackField::Name),
+            "artists" => Ok(TrackField::Artists),
+            "album" => Ok(TrackField::Album),
+            "duration-ms" => Ok(TrackField::DurationMs),
+            "isrc" => Ok(TrackField::Isrc),
+            "popularity" => Ok(TrackField::Popularity),
+            "explicit" => Ok(TrackField::Explicit),
+            "track-number" => Ok(TrackField::TrackNumber),
+            _ => Err(format!("Unknown track field \"{}\"", s)),
+        }
+    }
+}
+
+/// Options for `shuffle-library`.
+#[derive(StructOpt, Debug)]
+#[structopt(rename_all = "kebab-case")]
+pub struct ShuffleLibraryOpts {
+    #[structopt(flatten)]
+    pub groups: GroupOpts,
+    /// Pick the source playlists and target duration from a menu. This is ignored if stdin is not
+    /// a terminal.
+    #[structopt(long)]
+    pub interactive: bool,
+    /// How long the shuffle should be (like `90m`, `2h30m` or a number of minutes). By default, it
+    /// is about 20 hours.
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    pub target_duration: Option<Duration>,
+    /// Fail instead of writing a shuffle shorter than this (like `2h`), which usually means the
+    /// filters left too little of the library.
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    pub min_duration: Option<Duration>,
+    /// Only warn when the shuffle is shorter than `--min-duration`, and write it anyway.
+    #[structopt(long, requires = "min-duration")]
+    pub allow_short: bool,
+    /// If the shuffle contains exactly the tracks already in the output playlist, reorder them in
+    /// place instead of clearing and re-adding them.
+    #[structopt(long, conflicts_with_all = &["append", "prepend"])]
+    pub reorder: bool,
+    /// Add the shuffle to the end of the output playlist instead of replacing its contents.
+    #[structopt(long, conflicts_with = "prepend")]
+    pub append: bool,
+    /// Add the shuffle to the start of the output playlist instead of replacing its contents.
+    #[structopt(long)]
+    pub prepend: bool,
+    /// Keep tracks which were added to the output playlist by hand since the last shuffle.
+    #[structopt(long, conflicts_with_all = &["append", "prepend"])]
+    pub preserve_shuffle_extras: bool,
+    /// Write each shuffle to a new playlist named by `--name-template` and only keep the newest `N`
+    /// of them.
+    #[structopt(long, value_name = "N")]
+    pub rotate: Option<usize>,
+    /// Split the shuffle into playlists of at most `N` tracks, numbered after the output playlist
+    /// ("Shuffle 1", "Shuffle 2", ...).
+    #[structopt(long, value_name = "N", conflicts_with = "rotate")]
+    pub split_size: Option<usize>,
+    /// Name of the playlists created by `--rotate`. `{date}` is replaced with the current date.
+    #[structopt(long, default_value = "Shuffle {date}", parse(try_from_str = parse_name_template))]
+    pub name_template: String,
+    /// The description to give the output playlist, instead of the default.
+    #[structopt(long, value_name = "TEXT")]
+    pub description: Option<String>,
+    /// Make the output playlist public.
+    #[structopt(long)]
+    pub public: bool,
+    /// Make the output playlist collaborative. Collaborative playlists must be private.
+    #[structopt(long)]
+    pub collaborative: bool,
+    /// What to shuffle: `album` keeps the tracks of each track group together and in order, `track`
+    /// shuffles every track on its own.
+    #[structopt(long, default_value = "album")]
+    pub shuffle_mode: ShuffleMode,
+    /// Once the track groups are picked, alternate between them track by track instead of playing
+    /// each group whole.
+    #[structopt(long)]
+    pub interleave: bool,
+    /// How to order the track groups: `random`, `artist` or `album` (alphabetically), `added` (library
+    /// order, like `--keep-playlist-order`) or `duration` (shortest first).
+    #[structopt(long, default_value = "random")]
+    pub order: Order,
+    /// Don't shuffle: add track groups in the order they appear in the source playlists.
+    #[structopt(long, conflicts_with = "order")]
+    pub keep_playlist_order: bool,
+    /// Skip track groups which would take the shuffle past `N` tracks by their artist.
+    #[structopt(long, alias = "max-per-artist", value_name = "N")]
+    pub artist_limit: Option<usize>,
+    /// Stop adding track groups once the shuffle has `N` tracks, even if it is shorter than the goal
+    /// duration. The last group can take the shuffle past `N` unless `--no-partial-albums` is set.
+    #[structopt(long, value_name = "N")]
+    pub max_tracks: Option<usize>,
+    /// Never go over `--max-tracks`, leaving out the group which would.
+    #[structopt(long, requires = "max-tracks")]
+    pub no_partial_albums: bool,
+    /// Keep repeats of the same track or album in the shuffle, which are removed by default.
+    #[structopt(long)]
+    pub allow_duplicates: bool,
+    /// Removing repeats is the default now, so this does nothing. It is kept so old scripts work.
+    #[structopt(long, hidden = true, conflicts_with = "allow-duplicates")]
+    pub dedupe_tracks: bool,
+    /// Re-fetch the output playlist after writing it to check that every track made it in.
+    #[structopt(long)]
+    pub verify: bool,
+    /// A JPEG image to upload as the output playlist's cover.
+    #[structopt(long, value_name = "IMAGE", parse(from_os_str))]
+    pub cover: Option<PathBuf>,
+    /// Also write the shuffle to this file: an M3U playlist for `.m3u` or `.m3u8`, or a JSON list of
+    /// tracks for `.json`.
+    #[structopt(long, value_name = "PATH", parse(from_os_str))]
+    pub export: Option<PathBuf>,
+    /// Keep running and generate a new shuffle at this interval (like `6h`), until interrupted.
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    pub every: Option<Duration>,
+    /// Only write a new shuffle if a source playlist changed since the last one.
+    #[structopt(long)]
+    pub if_changed: bool,
+    /// Print a summary of the shuffle: how many tracks, track groups and artists it has, its longest
+    /// and shortest groups, and how many groups didn't fit in the target duration.
+    #[structopt(long)]
+    pub summary: bool,
+    /// Print the summary as a line of JSON instead: on stdout, or on stderr with `--dry-run`. With
+    /// `--json`, it is the `summary` of the result instead.
+    #[structopt(long)]
+    pub summary_json: bool,
+    /// Replace the contents of the output playlist without asking, even if it has tracks which
+    /// weren't written by a shuffle.
+    #[structopt(long, short, alias = "force")]
+    pub yes: bool,
+    /// Print the generated shuffle instead of writing it to the output playlist.
+    #[structopt(long)]
+    pub dry_run: bool,
+    /// Forget which albums were picked by recent shuffles, which are otherwise less likely to be
+    /// picked again for a week.
+    #[structopt(long)]
+    pub forget_history: bool,
+    /// Seed the shuffle, so running again with the same seed and library gives the same playlist.
+    /// This ignores the shuffle history.
+    #[structopt(long, value_name = "N")]
+    pub seed: Option<u64>,
+    /// Start playing the output playlist once it is written.
+    #[structopt(long)]
+    pub start_playing: bool,
+    /// The name or ID of the device to start playing on. By default, the active device is used.
+    #[structopt(long, value_name = "NAME")]
+    pub device: Option<String>,
+}
+
+// Options for loading and filtering the track groups `shuffle-library` and `queue-next-albums` pick
+// from. These aren't doc comments, since structopt would show them as the description of every
+// command which flattens these options.
+#[derive(StructOpt, Debug)]
+#[structopt(rename_all = "kebab-case")]
+pub struct GroupOpts {
+    #[structopt(flatten)]
+    pub source: SourceOpts,
+    /// Only keep track groups whose artists are tagged with this genre. Can be given multiple times.
+    #[structopt(long = "genre", number_of_values = 1)]
+    pub genres: Vec<String>,
+    /// Only keep track groups by the artist with this name, ID or URL. Can be given multiple times.
+    #[structopt(long = "artist", number_of_values = 1)]
+    pub artists: Vec<String>,
+    /// Drop track groups whose artists are tagged with this genre. Can be given multiple times.
+    #[structopt(long = "exclude-genre", number_of_values = 1)]
+    pub exclude_genres: Vec<String>,
+    /// The country (like `US` or `DE`) whose catalog decides which tracks can be played. By default,
+    /// the country of the user's account.
+    #[structopt(long, value_name = "CC", parse(try_from_str = parse_market))]
+    pub market: Option<String>,
+    /// Keep tracks which can't be played in the market instead of leaving them out.
+    #[structopt(long)]
+    pub include_unavailable: bool,
+    /// Drop track groups longer than this (like `3h` or `150m`), such as DJ mixes or long
+    /// compilations.
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    pub max_album_duration: Option<Duration>,
+    /// A JSON file listing sets of album IDs to treat as one album, like `[["ID1", "ID2"]]`.
+    #[structopt(long, value_name = "FILE", parse(from_os_str))]
+    pub album_aliases: Option<PathBuf>,
+    /// Which tracks to keep by their explicit flag: `all`, `off` (no explicit tracks) or `only`
+    /// (only explicit tracks).
+    #[structopt(long, default_value = "all")]
+    pub explicit_filter: ExplicitFilter,
+    /// Only shuffle track groups containing this track (an ID, URI or link).
+    #[structopt(long, value_name = "TRACK", parse(try_from_str = parse_track_id))]
+    pub contains_track: Option<String>,
+    /// Skip source playlists shorter than this in total (like `30m`).
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    pub min_playlist_duration: Option<Duration>,
+    /// Treat each playlist whose total length makes it look like a single album as one track group,
+    /// named after the playlist, instead of splitting it by album.
+    #[structopt(long)]
+    pub playlist_as_album: bool,
+    /// Splitting every playlist by album is the default now, so this does nothing. It is kept so old
+    /// scripts work.
+    #[structopt(long, hidden = true, conflicts_with = "playlist-as-album")]
+    pub no_single_group: bool,
+    /// With `--playlist-as-album`, playlists at least this long are treated as a single album.
+    #[structopt(long, default_value = "45m", parse(try_from_str = parse_duration))]
+    pub playlist_as_album_min: Duration,
+    /// With `--playlist-as-album`, playlists at most this long are treated as a single album.
+    #[structopt(long, default_value = "90m", parse(try_from_str = parse_duration))]
+    pub playlist_as_album_max: Duration,
+    /// Runs of tracks from the same album shorter than this aren't counted as an album and are
+    /// dropped. With `--group-by session`, this applies to sessions instead.
+    #[structopt(long, default_value = "10m", parse(try_from_str = parse_duration))]
+    pub min_album_length: Duration,
+    /// Also shuffle the albums saved in the user's library, each as its own track group.
+    #[structopt(long, conflicts_with = "from-user")]
+    pub include_saved_albums: bool,
+    /// Also shuffle the user's liked songs, grouped by album like a playlist.
+    #[structopt(long, conflicts_with = "from-user")]
+    pub include_liked: bool,
+    /// How tracks are split into track groups: `album` or `session` (tracks added to a playlist
+    /// around the same time).
+    #[structopt(long, default_value = "album")]
+    pub group_by: GroupBy,
+    /// With `--group-by session`, the longest gap between tracks being added to a playlist for
+    /// them to count as the same session.
+    #[structopt(long, default_value = "10m", parse(try_from_str = parse_duration))]
+    pub session_gap: Duration,
+    /// Skip source playlists which fail to load instead of giving up on the whole shuffle.
+    #[structopt(long, alias = "skip-errors")]
+    pub continue_on_error: bool,
+    /// Reuse the playlists loaded by the previous run if it was interrupted, instead of loading
+    /// every playlist again.
+    #[structopt(long)]
+    pub resume: bool,
+    /// Fetch every playlist again instead of using the tracks cached by recent runs.
+    #[structopt(long)]
+    pub refresh: bool,
+    /// How long the cached tracks of an unchanged playlist are used for.
+    #[structopt(long, value_name = "DURATION", default_value = "24h", parse(try_from_str = parse_duration))]
+    pub cache_ttl: Duration,
+    /// Only shuffle albums if the playlist contains (nearly) all of their tracks.
+    #[structopt(long)]
+    pub full_albums_only: bool,
+}
+
+// Options for which playlists in the library are sources of track groups (not a doc comment, for
+// the same reason as `GroupOpts`).
+#[derive(StructOpt, Debug)]
+#[structopt(rename_all = "kebab-case")]
+pub struct SourceOpts {
+    /// The name of the playlist to write the shuffle to. It is created if it does not exist.
+    #[structopt(long, value_name = "NAME", default_value = "Shuffle")]
+    pub output: String,
+    /// Shuffle the public playlists of the user with this ID (or profile URI or link) instead of your
+    /// own library. The shuffle is still written to your account.
+    #[structopt(long, value_name = "USER_ID", parse(try_from_str = parse_user_id))]
+    pub from_user: Option<String>,
+    /// Only use the playlist with this name, ID or URL as a source, instead of the whole library.
+    /// Can be given multiple times.
+    #[structopt(long = "source", value_name = "NAME_OR_ID", number_of_values = 1)]
+    pub sources: Vec<String>,
+    /// Don't use the playlist with this name as a source. Can be given multiple times.
+    #[structopt(long = "exclude", value_name = "NAME", number_of_values = 1)]
+    pub exclude_playlists: Vec<String>,
+    /// Don't use playlists with names matching this regular expression as sources. Can be given
+    /// multiple times.
+    #[structopt(long = "exclude-regex",
+                value_name = "PATTERN",
+                number_of_values = 1,
+                parse(try_from_str = Regex::new))]
+    pub exclude_playlist_patterns: Vec<Regex>,
 }
 
 #[derive(StructOpt, Debug)]
 #[structopt(rename_all = "kebab-case")]
 pub struct BaseOpts {
+    /// Where to keep the login token and other state (by default, the platform's config directory,
+    /// such as `~/.config/spotctl` on Linux).
+    #[structopt(long, global = true, parse(from_os_str))]
+    pub config_dir: Option<PathBuf>,
+    /// Print the scopes requested when logging in next to the scopes actually granted.
+    #[structopt(long, global = true)]
+    pub scope_debug: bool,
+    /// Print the result of the command as JSON on stdout. Progress messages still go to stderr.
+    #[structopt(long, global = true)]
+    pub json: bool,
+    /// Only print warnings and errors, skipping progress messages and optional reports like the
+    /// albums added to and removed from the shuffle playlist.
+    #[structopt(long, short, global = true)]
+    pub quiet: bool,
+    /// Print more details of what is happening. Give it twice (`-vv`) to also trace every request.
+    #[structopt(long, short, global = true, parse(from_occurrences))]
+    pub verbose: u8,
+    /// How many times to retry a request which failed from a network or server error.
+    #[structopt(long, global = true, value_name = "N", default_value = "3")]
+    pub max_retries: u32,
+    /// How many pages of a long list, like the tracks of a big playlist, to fetch at once.
+    #[structopt(long, global = true, value_name = "N", default_value = "4")]
+    pub concurrency: usize,
+    /// How many items to ask for in each page of a long list. By default this is the most Spotify
+    /// allows: 50, or 100 for the tracks of a playlist.
+    #[structopt(long, global = true, value_name = "N")]
+    pub page_size: Option<u32>,
     #[structopt(subcommand)]
     pub command: BaseCmd,
 }
+
+/// Which tracks to keep based on whether they are explicit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExplicitFilter {
+    /// Keep every track.
+    All,
+    /// Remove explicit tracks.
+    Off,
+    /// Keep only explicit tracks.
+    Only,
+}
+
+impl FromStr for ExplicitFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" | "on" => Ok(ExplicitFilter::All),
+            "off" => Ok(ExplicitFilter::Off),
+            "only" => Ok(ExplicitFilter::Only),
+            _ => Err(format!("Unknown explicit filter \"{}\" (expected \"all\", \"off\" or \"only\")", s)),
+        }
+    }
+}
+
+/// What `shuffle-library` shuffles.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShuffleMode {
+    /// Whole track groups, keeping their tracks in order.
+    Album,
+    /// Individual tracks.
+    Track,
+}
+
+impl FromStr for ShuffleMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "album" => Ok(ShuffleMode::Album),
+            "track" => Ok(ShuffleMode::Track),
+            _ => Err(format!("Unknown shuffle mode \"{}\" (expected \"album\" or \"track\")", s)),
+        }
+    }
+}
+
+/// The order `shuffle-library` puts the picked track groups in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Order {
+    Random,
+    /// Alphabetically by primary artist, then by name.
+    Artist,
+    /// Alphabetically by name.
+    Album,
+    /// In the order they were added to the library: by source playlist, then saved albums.
+    Added,
+    /// Shortest first.
+    Duration,
+}
+
+impl FromStr for Order {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(Order::Random),
+            "artist" => Ok(Order::Artist),
+            "album" => Ok(Order::Album),
+            "added" => Ok(Order::Added),
+            "duration" => Ok(Order::Duration),
+            _ => Err(format!("Unknown order \"{}\" (expected \"random\", \"artist\", \"album\", \"added\" \
+                              or \"duration\")",
+                             s)),
+        }
+    }
+}
+
+/// How tracks are split into track groups.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GroupBy {
+    /// Consecutive tracks from the same album.
+    Album,
+    /// Tracks which were added to a playlist around the same time.
+    Session,
+}
+
+impl FromStr for GroupBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "album" => Ok(GroupBy::Album),
+            "session" => Ok(GroupBy::Session),
+            _ => Err(format!("Unknown grouping \"{}\" (expected \"album\" or \"session\")", s)),
+        }
+    }
+}
+
+/// How `list-playlists` orders its output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlaylistSort {
+    Name,
+    /// Most tracks first.
+    Tracks,
+}
+
+impl FromStr for PlaylistSort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(PlaylistSort::Name),
+            "tracks" => Ok(PlaylistSort::Tracks),
+            _ => Err(format!("Unknown sort order \"{}\" (expected \"name\" or \"tracks\")", s)),
+        }
+    }
+}
+
+/// Parse a duration like `90m`, `2h30m`, `45s` or a bare number of minutes.
+pub fn parse_duration(input: &str) -> Result<Duration, String> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err("Duration must not be empty".to_owned())
+    }
+
+    let too_long = || format!("Duration \"{}\" is too long", input);
+
+    if input.chars().all(|c| c.is_ascii_digit()) {
+        let minutes: u64 = input.parse().map_err(|_| too_long())?;
+        return minutes.checked_mul(60).map(Duration::from_secs).ok_or_else(too_long)
+    }
+
+    let mut total = 0u64;
+    let mut number = String::new();
+    for c in input.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue
+        }
+
+        let scale = match c {
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(format!("Invalid character '{}' in duration \"{}\"", c, input)),
+        };
+        if number.is_empty() {
+            return Err(format!("Missing number before '{}' in duration \"{}\"", c, input))
+        }
+        let value: u64 = number.parse().map_err(|_| too_long())?;
+        total = value
+            .checked_mul(scale)
+            .and_then(|secs| total.checked_add(secs))
+            .ok_or_else(too_long)?;
+        number.clear();
+    }
+
+    if !number.is_empty() {
+        return Err(format!("Missing unit after \"{}\" in duration \"{}\"", number, input))
+    }
+
+    Ok(Duration::from_secs(total))
+}
+
+/// Parse a market, which is an ISO 3166-1 alpha-2 country code like `US`.
+pub fn parse_market(input: &str) -> Result<String, String> {
+    let input = input.trim();
+    if input.len() != 2 || !input.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(format!("\"{}\" is not a two-letter country code", input))
+    }
+    Ok(input.to_ascii_uppercase())
+}
+
+/// Parse the ID of a user, given as an ID, a `spotify:user:` URI or a profile link.
+pub fn parse_user_id(input: &str) -> Result<String, String> {
+    let input = input.trim();
+    let id = if let Some(id) = input.strip_prefix("spotify:user:") {
+        id
+    } else if let Some(pos) = input.find("open.spotify.com/user/") {
+        let path = &input[pos + "open.spotify.com/user/".len()..];
+        path.split(['/', '?', '#']).next().unwrap_or("")
+    } else {
+        input
+    };
+
+    if id.is_empty() || id.contains(|c: char| c.is_whitespace() || c == '/' || c == ':') {
+        return Err(format!("\"{}\" is not a valid Spotify user ID", input))
+    }
+    Ok(id.to_owned())
+}
+
+/// Parse the ID of a track, given as an ID, URI or link.
+pub fn parse_track_id(input: &str) -> Result<String, String> {
+    parse_spotify_id(SpotifyKind::Track, input)
+}
+
+/// Parse a playlist name template, which must contain the `{date}` placeholder exactly once.
+pub fn parse_name_template(input: &str) -> Result<String, String> {
+    if input.matches("{date}").count() != 1 {
+        return Err(format!("Name template \"{}\" must contain \"{{date}}\" exactly once", input))
+    }
+    Ok(input.to_owned())
+}
+
+/// The kind of Spotify resource an ID refers to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpotifyKind {
+    Album,
+    Artist,
+    Playlist,
+    Track,
+}
+
+impl SpotifyKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SpotifyKind::Album => "album",
+            SpotifyKind::Artist => "artist",
+            SpotifyKind::Playlist => "playlist",
+            SpotifyKind::Track => "track",
+        }
+    }
+}
+
+/// Parse a user-provided Spotify resource reference into a bare ID. This accepts URIs like
+/// `spotify:track:ID`, URLs like `https://open.spotify.com/track/ID?si=...` and bare IDs. The
+/// resource kind of URIs and URLs must match `kind`.
+pub fn parse_spotify_id(kind: SpotifyKind, input: &str) -> Result<String, String> {
+    let input = input.trim();
+
+    let (found_kind, id) = if input.starts_with("spotify:") {
+        // `spotify:track:ID` or the legacy `spotify:user:USER:playlist:ID`
+        let parts: Vec<&str> = input.split(':').collect();
+        match parts.as_slice() {
+            [_, k, id] => (Some(*k), *id),
+            [_, "user", _, k, id] => (Some(*k), *id),
+            _ => return Err(format!("Malformed Spotify URI \"{}\"", input)),
+        }
+    } else if let Some(pos) = input.find("open.spotify.com/") {
+        let path = &input[pos + "open.spotify.com/".len()..];
+        let path = path.split(['?', '#']).next().unwrap_or("");
+        let parts: Vec<&str> = path
+            .split('/')
+            .filter(|p| !p.is_empty() && !p.starts_with("intl-"))
+            .collect();
+        match parts.as_slice() {
+            [k, id] => (Some(*k), *id),
+            ["user", _, k, id] => (Some(*k), *id),
+            _ => return Err(format!("Malformed Spotify URL \"{}\"", input)),
+        }
+    } else {
+        (None, input)
+    };
+
+    if let Some(found_kind) = found_kind {
+        if found_kind != kind.as_str() {
+            return Err(format!("Expected a Spotify {} but \"{}\" refers to a {}",
+                               kind.as_str(), input, found_kind));
+        }
+    }
+
+    if id.len() != 22 || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!("\"{}\" is not a valid Spotify {} ID", id, kind.as_str()));
+    }
+
+    Ok(id.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    const ID: &str = "6rqhFgbbKwnb9MLmUQDhG6";
+
+    fn parse_args(args: &[&str]) -> Result<BaseOpts, String> {
+        BaseOpts::from_iter_safe(std::iter::once("spotctl").chain(args.iter().cloned()))
+            .map_err(|e| e.message)
+    }
+
+    #[test]
+    fn queue_next_albums_takes_group_options() {
+        let opts = parse_args(&["queue-next-albums", "3", "--exclude-genre", "jazz", "--exclude", "Old",
+                                "--seed", "7", "--device", "Kitchen"]).unwrap();
+        match opts.command {
+            BaseCmd::QueueNextAlbums { count, seed, device, opts } => {
+                assert_eq!(count, 3);
+                assert_eq!(seed, Some(7));
+                assert_eq!(device.as_deref(), Some("Kitchen"));
+                assert_eq!(opts.exclude_genres, vec!["jazz".to_owned()]);
+                assert_eq!(opts.source.exclude_playlists, vec!["Old".to_owned()]);
+            }
+            command => panic!("Parsed the wrong command: {:?}", command),
+        }
+
+        assert!(parse_args(&["queue-next-albums", "3", "--target-duration", "2h"]).is_err());
+        let conflicting = ["queue-next-albums", "3", "--from-user", "someone", "--include-liked"];
+        assert!(parse_args(&conflicting).is_err());
+    }
+
+    #[test]
+    fn list_playlists_takes_source_options() {
+        let opts = parse_args(&["list-playlists", "--sort", "name", "--exclude-regex", "^Old",
+                                "--output", "Mix"]).unwrap();
+        match opts.command {
+            BaseCmd::ListPlaylists { sort, opts } => {
+                assert_eq!(sort, Some(PlaylistSort::Name));
+                assert_eq!(opts.output, "Mix");
+                assert_eq!(opts.exclude_playlist_patterns.len(), 1);
+            }
+            command => panic!("Parsed the wrong command: {:?}", command),
+        }
+
+        assert!(parse_args(&["list-playlists", "--genre", "rock"]).is_err());
+        assert!(parse_args(&["list-playlists", "--include-liked"]).is_err());
+    }
+
+    #[test]
+    fn parse_duration_units() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90 * 60)));
+        assert_eq!(parse_duration("2h30m"), Ok(Duration::from_secs(9000)));
+        assert_eq!(parse_duration("45s"), Ok(Duration::from_secs(45)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("10x").is_err());
+        assert!(parse_duration("10m5").is_err());
+    }
+
+    #[test]
+    fn parse_duration_overflow() {
+        for input in &["999999999999999999", "99999999999999999999", "999999999999999999h",
+                       "18446744073709551615s1s", "99999999999999999999999s"] {
+            let err = parse_duration(input).unwrap_err();
+            assert!(err.contains("is too long"), "{}: {}", input, err);
+        }
+    }
+
+    #[test]
+    fn parse_spotify_id_uri() {
+        assert_eq!(parse_spotify_id(SpotifyKind::Track, &format!("spotify:track:{}", ID)), Ok(ID.to_owned()));
+    }
+
+    #[test]
+    fn parse_spotify_id_legacy_playlist_uri() {
+        let uri = format!("spotify:user:someone:playlist:{}", ID);
+        assert_eq!(parse_spotify_id(SpotifyKind::Playlist, &uri), Ok(ID.to_owned()));
+    }
+
+    #[test]
+    fn parse_spotify_id_url() {
+        let url = format!("https://open.spotify.com/track/{}?si=abc123", ID);
+        assert_eq!(parse_spotify_id(SpotifyKind::Track, &url), Ok(ID.to_owned()));
+    }
+
+    #[test]
+    fn parse_spotify_id_intl_url() {
+        let url = format!("https://open.spotify.com/intl-de/album/{}#top", ID);
+        assert_eq!(parse_spotify_id(SpotifyKind::Album, &url), Ok(ID.to_owned()));
+    }
+
+    #[test]
+    fn parse_spotify_id_bare() {
+        assert_eq!(parse_spotify_id(SpotifyKind::Artist, &format!("  {} ", ID)), Ok(ID.to_owned()));
+    }
+
+    #[test]
+    fn parse_spotify_id_kind_mismatch() {
+        let err = parse_spotify_id(SpotifyKind::Playlist, &format!("spotify:album:{}", ID)).unwrap_err();
+        assert!(err.contains("refers to a album"), "{}", err);
+        let url = format!("https://open.spotify.com/artist/{}", ID);
+        assert!(parse_spotify_id(SpotifyKind::Track, &url).is_err());
+    }
+
+    #[test]
+    fn parse_spotify_id_bad_length() {
+        assert!(parse_spotify_id(SpotifyKind::Track, "6rqhFgbbKwnb9MLmUQDhG").is_err());
+        assert!(parse_spotify_id(SpotifyKind::Track, "spotify:track:6rqhFgbbKwnb9MLmUQDhG6x").is_err());
+        assert!(parse_spotify_id(SpotifyKind::Track, "6rqhFgbbKwnb9MLmUQDh-6").is_err());
+    }
+}
@@ -0,0 +1,116 @@
+//! Module for the errors `spotctl` reports, so scripts can tell the kinds of failure apart by the
+//! exit code.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use rspotify::spotify::client::ApiError;
+
+#[derive(Debug)]
+pub enum SpotctlError {
+    /// Logging in to Spotify failed.
+    Auth(String),
+    /// Spotify rejected the login token part way through.
+    TokenExpired,
+    /// A request to Spotify failed.
+    Api(String),
+    /// A file or command line value could not be parsed.
+    Parse(String),
+    Io(io::Error),
+    Other(String),
+}
+
+impl SpotctlError {
+    /// The process exit code for this kind of error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            SpotctlError::Other(_) => 1,
+            SpotctlError::Parse(_) => 2,
+            SpotctlError::Auth(_) => 3,
+            SpotctlError::TokenExpired => 4,
+            SpotctlError::Api(_) => 5,
+            SpotctlError::Io(_) => 6,
+        }
+    }
+}
+
+impl fmt::Display for SpotctlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpotctlError::Auth(msg) => write!(f, "Could not log in to Spotify: {}", msg),
+            SpotctlError::TokenExpired => write!(f, "Spotify rejected the login token"),
+            SpotctlError::Api(msg) => write!(f, "Spotify API error: {}", msg),
+            SpotctlError::Parse(msg) | SpotctlError::Other(msg) => write!(f, "{}", msg),
+            SpotctlError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for SpotctlError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SpotctlError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<failure::Error> for SpotctlError {
+    fn from(e: failure::Error) -> Self {
+        match e.downcast_ref::<ApiError>() {
+            Some(ApiError::Unauthorized) => SpotctlError::TokenExpired,
+            Some(ApiError::RateLimited(_)) => SpotctlError::Api("rate limited".to_owned()),
+            Some(ApiError::Other(status)) => SpotctlError::Api(format!("HTTP {}", status)),
+            None => SpotctlError::Api(e.to_string()),
+        }
+    }
+}
+
+impl From<reqwest::Error> for SpotctlError {
+    fn from(e: reqwest::Error) -> Self {
+        SpotctlError::Api(e.to_string())
+    }
+}
+
+impl From<io::Error> for SpotctlError {
+    fn from(e: io::Error) -> Self {
+        SpotctlError::Io(e)
+    }
+}
+
+impl From<dialoguer::Error> for SpotctlError {
+    fn from(e: dialoguer::Error) -> Self {
+        SpotctlError::Io(io::Error::other(e))
+    }
+}
+
+impl From<ctrlc::Error> for SpotctlError {
+    fn from(e: ctrlc::Error) -> Self {
+        SpotctlError::Other(e.to_string())
+    }
+}
+
+impl From<std::num::ParseIntError> for SpotctlError {
+    fn from(e: std::num::ParseIntError) -> Self {
+        SpotctlError::Parse(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for SpotctlError {
+    fn from(e: serde_json::Error) -> Self {
+        SpotctlError::Parse(e.to_string())
+    }
+}
+
+impl From<String> for SpotctlError {
+    fn from(msg: String) -> Self {
+        SpotctlError::Other(msg)
+    }
+}
+
+impl From<&str> for SpotctlError {
+    fn from(msg: &str) -> Self {
+        SpotctlError::Other(msg.to_owned())
+    }
+}
@@ -0,0 +1,38 @@
+//! Module for fuzzy string matching, used to suggest what the user might have meant when a name
+//! does not match anything.
+
+/// The Levenshtein edit distance between `a` and `b`, ignoring case.
+pub fn distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.to_lowercase().chars().collect();
+    let b: Vec<char> = b.to_lowercase().chars().collect();
+
+    // Classic dynamic programming over a single row
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + if ca == cb { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Get up to 3 of the `candidates` which are close to `name`, closest first. Exact matches are not
+/// included.
+pub fn closest_matches<'a, I>(name: &str, candidates: I) -> Vec<&'a str>
+    where I: IntoIterator<Item = &'a str> {
+    let max_distance = (name.chars().count() / 3).max(1);
+
+    let mut matches: Vec<(usize, &str)> = candidates
+        .into_iter()
+        .map(|candidate| (distance(name, candidate), candidate))
+        .filter(|(dist, _)| *dist > 0 && *dist <= max_distance)
+        .collect();
+    matches.sort();
+    matches.dedup_by(|a, b| a.1 == b.1);
+
+    matches.into_iter().take(3).map(|(_, candidate)| candidate).collect()
+}
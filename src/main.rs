@@ -1,42 +1,313 @@
+extern crate base64;
+extern crate chrono;
+extern crate ctrlc;
+extern crate dialoguer;
+extern crate directories;
 extern crate failure;
 extern crate rand;
+extern crate regex;
+extern crate reqwest;
 extern crate rspotify;
+extern crate serde;
+extern crate serde_json;
+extern crate env_logger;
+#[macro_use]
+extern crate log;
 #[macro_use]
 extern crate structopt;
+extern crate toml;
 
 mod cmd;
+mod error;
+mod fuzzy;
 
-use std::error::Error;
-use std::collections::HashSet;
+use std::cell::Cell;
+use std::env;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, IsTerminal, Write};
 use std::iter::FromIterator;
-use std::time::Duration;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex, Once};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::process;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::thread;
 
-use rand::thread_rng;
+use chrono::{Local, NaiveDate};
+use dialoguer::{Confirm, Input, MultiSelect, Select};
+use directories::BaseDirs;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
-use rspotify::spotify::oauth2::{SpotifyOAuth, SpotifyClientCredentials};
+use rspotify::spotify::oauth2::{SpotifyOAuth, SpotifyClientCredentials, TokenInfo};
 use rspotify::spotify::util::get_token;
 use rspotify::spotify::client::Spotify;
+use rspotify::spotify::model::album::FullAlbum;
+use rspotify::spotify::model::artist::{FullArtist, SimplifiedArtist};
+use rspotify::spotify::model::device::Device;
 use rspotify::spotify::model::playlist::{SimplifiedPlaylist, PlaylistTrack};
 use rspotify::spotify::model::page::Page;
+use rspotify::spotify::model::track::FullTrack;
 use rspotify::spotify::client::ApiError;
+use rspotify::spotify::senum::Country;
+use serde::{Deserialize, Serialize};
 use structopt::StructOpt;
 
-type Result<T> = std::result::Result<T, Box<dyn Error>>;
+use error::SpotctlError;
+use log::{Level, LevelFilter};
+
+type Result<T> = std::result::Result<T, SpotctlError>;
+
+/// The user's settings file, relative to the config directory.
+const CONFIG_FILE: &str = "config.toml";
+
+/// Where the Spotify redirects to after logging in, unless configured otherwise.
+const DEFAULT_REDIRECT_URI: &str = "http://localhost:8888/callback";
+
+/// Where the Spotify login token is cached, relative to the config directory.
+const TOKEN_CACHE_FILE: &str = "token_cache.json";
+
+/// Where the track IDs of the last shuffle written to each output playlist are recorded, relative to
+/// the config directory.
+const LAST_SHUFFLE_FILE: &str = "last_shuffle.json";
+
+/// Where the snapshot IDs of the source playlists of the last shuffle written to each output are
+/// recorded for `--if-changed`, relative to the config directory.
+const LIBRARY_SNAPSHOTS_FILE: &str = "library_snapshots.json";
+
+/// Where `shuffle-library` records the tracks of each playlist it has loaded so an interrupted run can
+/// be resumed, relative to the config directory.
+const RESUME_FILE: &str = "shuffle_resume.jsonl";
+
+/// Like `RESUME_FILE`, but for `queue-next-albums`, so it doesn't clobber an interrupted shuffle.
+const QUEUE_RESUME_FILE: &str = "queue_resume.jsonl";
+
+/// Where the tracks of recently loaded playlists are cached, relative to the config directory.
+const LIBRARY_CACHE_FILE: &str = "library_cache.json";
+
+/// Where the times each album was picked for a shuffle are recorded, relative to the config
+/// directory.
+const SHUFFLE_HISTORY_FILE: &str = "shuffle_history.json";
+
+/// How long an album being picked for a shuffle makes it less likely to be picked again.
+const SHUFFLE_HISTORY_WINDOW: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// Playlists generated by Spotify, which are never used as a source of track groups. The output
+/// playlist is excluded too, but its name is configurable.
+const BANNED_PLAYLIST_NAMES: [&str; 3] = ["Discover Weekly", "Starred", "Liked from Radio"];
+
+/// How many times `Client::call_api` retries a rate limited request before giving up.
+const MAX_RATE_LIMIT_RETRIES: u32 = 10;
+
+/// The longest `Client::call_api` waits between retries when Spotify doesn't say how long to wait.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(64);
+
+/// Get how long to wait before retry number `attempt` (counting from 0) of a request. This is
+/// `server_timeout` seconds if Spotify gave one, otherwise it doubles with every attempt from one
+/// second up to `MAX_RETRY_DELAY`.
+fn retry_delay(attempt: u32, server_timeout: Option<usize>) -> Duration {
+    match server_timeout {
+        Some(secs) => Duration::from_secs(secs as u64),
+        None => Duration::from_secs(1u64.checked_shl(attempt).unwrap_or(u64::MAX)).min(MAX_RETRY_DELAY),
+    }
+}
+
+/// Describe `error` if it is worth retrying the request: a server error or a network failure. Client
+/// errors, like a bad token or a missing playlist, will fail again and give `None`.
+fn transient_error(error: &failure::Error) -> Option<String> {
+    if let Some(ApiError::Other(status)) = error.downcast_ref() {
+        return if *status >= 500 { Some(format!("HTTP {}", status)) } else { None }
+    }
+    match error.downcast_ref::<reqwest::Error>() {
+        Some(e) if e.is_timeout() => Some("timed out".to_owned()),
+        Some(e) if e.is_server_error() => e.status().map(|status| format!("HTTP {}", status.as_u16())),
+        Some(e) if e.is_http() => Some(e.to_string()),
+        _ => None,
+    }
+}
+
+thread_local! {
+    /// Whether this thread is in `catch_quiet_unwind`, so a panic is expected and shouldn't be
+    /// reported.
+    static IN_QUIET_UNWIND: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run `func`, catching any panic without the panic hook printing its message and backtrace.
+fn catch_quiet_unwind<F, T>(func: F) -> thread::Result<T>
+    where F: Fn() -> T {
+    // The hook is shared by every thread, so install one which checks the flag of the panicking one
+    static INSTALL_HOOK: Once = Once::new();
+    INSTALL_HOOK.call_once(|| {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !IN_QUIET_UNWIND.with(|quiet| quiet.get()) {
+                default_hook(info)
+            }
+        }));
+    });
+
+    IN_QUIET_UNWIND.with(|quiet| quiet.set(true));
+    let res = panic::catch_unwind(AssertUnwindSafe(func));
+    IN_QUIET_UNWIND.with(|quiet| quiet.set(false));
+    res
+}
+
+/// Resolve the directory `spotctl` keeps its state in, creating it if needed. This is `override_dir`
+/// if given, otherwise `spotctl` in the platform's config directory: `$XDG_CONFIG_HOME` (or
+/// `~/.config`) on Linux, `~/Library/Application Support` on macOS and `%APPDATA%` on Windows.
+fn config_dir(override_dir: Option<&Path>) -> Result<PathBuf> {
+    let dir = match override_dir {
+        Some(dir) => dir.to_owned(),
+        None => BaseDirs::new()
+            .ok_or("Could not determine the home directory -- pass --config-dir")?
+            .config_dir()
+            .join("spotctl"),
+    };
+
+    fs::create_dir_all(&dir)
+        .map_err(|e| format!("Could not create config directory {}: {}", dir.display(), e))?;
+    Ok(dir)
+}
+
+/// Settings shared by every command.
+struct Settings {
+    pub config_dir: PathBuf,
+    pub scope_debug: bool,
+    /// Print the result of the command as JSON on stdout.
+    pub json: bool,
+    /// Skip optional reports, like the changes made to the shuffle playlist.
+    pub quiet: bool,
+    /// How many times a request which failed from a network or server error is retried.
+    pub max_retries: u32,
+    /// How many requests for the pages of a long list are made at once.
+    pub concurrency: usize,
+    /// How many items to ask for in each page of a long list, instead of the most Spotify allows.
+    pub page_size: Option<u32>,
+}
+
+/// The contents of `config.toml`. Every setting is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub redirect_uri: Option<String>,
+}
+
+impl Config {
+    /// Load `config.toml` from the config directory. A missing file is the same as an empty one.
+    pub fn load(config_dir: &Path) -> Result<Config> {
+        let path = config_dir.join(CONFIG_FILE);
+        match fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(contents.as_str())
+                .map_err(|e| SpotctlError::Parse(format!("Could not parse {}: {}", path.display(), e))),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(format!("Could not read {}: {}", path.display(), e).into()),
+        }
+    }
+}
 
 struct Client {
     native: Spotify,
+    /// For the endpoints `rspotify` does not support.
+    http: reqwest::Client,
     user_id: String,
+    config_dir: PathBuf,
+    max_retries: u32,
+    /// How many pages `get_all` fetches at once.
+    concurrency: usize,
+    /// The `--page-size` to use instead of the largest each endpoint allows.
+    page_size_override: Option<u32>,
 }
 
 impl Client {
-    pub fn new() -> Result<Client> {
-        let mut oauth = SpotifyOAuth::default()
-            .scope("user-library-read playlist-read-private playlist-modify-private playlist-modify-public")
-            .redirect_uri("http://localhost:8888/callback")
+    /// Every scope `spotctl` asks for when logging in.
+    const SCOPES: &'static str = "user-library-read user-read-private playlist-read-private \
+                                  playlist-modify-private playlist-modify-public user-read-playback-state \
+                                  user-modify-playback-state ugc-image-upload";
+
+    /// The parts of each page of playlist tracks to ask for. rspotify's models need every field
+    /// which isn't an `Option`, so those are kept even though `spotctl` doesn't read them all; what
+    /// is left out is mostly links, album details and the markets each track is available in.
+    const PLAYLIST_TRACK_FIELDS: &'static str = "href,limit,next,offset,previous,total,\
+        items(added_at,is_local,track(\
+        album(artists(external_urls,id,name,type),external_urls,id,images,name,type),\
+        artists(external_urls,id,name,type),\
+        disc_number,duration_ms,explicit,external_ids,external_urls,id,is_local,is_playable,linked_from,\
+        name,popularity,track_number,type,uri))";
+
+    /// Log in and create a client. `required_scopes` are the scopes the command needs; if the token
+    /// lacks any of them (because they were denied when logging in), this warns and offers to log in
+    /// again.
+    pub fn new(settings: &Settings, required_scopes: &[&str]) -> Result<Client> {
+        // rspotify sends every request through its own static reqwest client, which can't be
+        // configured and does not read the proxy environment variables
+        let proxy_vars = ["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy"];
+        if let Some(var) = proxy_vars.iter().find(|v| env::var_os(v).is_some()) {
+            warn!("{} is set, but spotctl can't use a proxy -- connecting directly", var);
+        }
+
+        // Creating the default also loads a `.env` file into the environment, so do it first; each
+        // environment variable takes precedence over the config file
+        let oauth = SpotifyOAuth::default();
+        let config = Config::load(&settings.config_dir)?;
+        let client_id = env::var("CLIENT_ID").ok().or(config.client_id).unwrap_or_default();
+        let client_secret = env::var("CLIENT_SECRET").ok().or(config.client_secret).unwrap_or_default();
+        let redirect_uri = env::var("REDIRECT_URI")
+            .ok()
+            .or(config.redirect_uri)
+            .unwrap_or_else(|| DEFAULT_REDIRECT_URI.to_owned());
+
+        let cache_path = settings.config_dir.join(TOKEN_CACHE_FILE);
+        let mut oauth = oauth
+            .client_id(client_id.as_str())
+            .client_secret(client_secret.as_str())
+            .scope(Self::SCOPES)
+            .redirect_uri(redirect_uri.as_str())
+            .cache_path(cache_path.clone())
             .build();
 
-        let native = match get_token(&mut oauth) {
+        // The cached token is reused (and refreshed once it expires, falling back to logging in again
+        // if the refresh is rejected), but rspotify panics on a cache it can't parse
+        if let Ok(cached) = fs::read_to_string(&cache_path) {
+            if serde_json::from_str::<TokenInfo>(cached.as_str()).is_err() {
+                warn!("Ignoring corrupt token cache {} -- logging in again", cache_path.display());
+                fs::remove_file(&cache_path)?;
+            }
+        }
+        let mut token_info = get_token(&mut oauth);
+        if let Some(granted) = token_info.as_ref().map(|t| t.scope.clone()) {
+            let granted: Vec<&str> = granted.split_whitespace().collect();
+            if settings.scope_debug {
+                let requested: Vec<&str> = Self::SCOPES.split_whitespace().collect();
+                info!("Requested scopes: {}", requested.join(" "));
+                info!("Granted scopes:   {}", granted.join(" "));
+            }
+
+            let missing: Vec<&str> = required_scopes
+                .iter()
+                .cloned()
+                .filter(|s| !granted.contains(s))
+                .collect();
+            if !missing.is_empty() {
+                warn!("The login token is missing scopes this command needs: {}",
+                      missing.join(" "));
+                let reauth = io::stdin().is_terminal()
+                    && Confirm::new()
+                        .with_prompt("Log in again to grant them?")
+                        .default(true)
+                        .interact()?;
+                if reauth {
+                    fs::remove_file(&cache_path)?;
+                    token_info = get_token(&mut oauth);
+                }
+            }
+        }
+
+        let native = match token_info {
             Some(token_info) => {
                 let client_credentials = SpotifyClientCredentials::default()
                     .token_info(token_info)
@@ -46,99 +317,437 @@ impl Client {
                     .build()
             }
             None => {
-                // TODO: Better error message here...rspotify should probably return an `Error`
-                return Err(Box::new(std::io::Error::new(std::io::ErrorKind::InvalidInput,
-                                                        "Could not create Spotify client")))
+                return Err(SpotctlError::Auth("no login token (check the client ID and secret)".to_owned()))
             }
         };
 
         let user_id = native.current_user()?.id;
 
-        Ok(Client { native, user_id })
+        Ok(Client {
+            native,
+            http: reqwest::Client::new(),
+            user_id,
+            config_dir: settings.config_dir.clone(),
+            max_retries: settings.max_retries,
+            concurrency: settings.concurrency,
+            page_size_override: settings.page_size,
+        })
     }
 
-    fn call_api<F, T>(func: F) -> std::result::Result<T, failure::Error>
+    fn call_api<F, T>(&self, func: F) -> std::result::Result<T, failure::Error>
         where F: Fn() -> std::result::Result<T, failure::Error> {
 
+        let mut transient_failures = 0;
+        let mut rate_limits = 0;
         loop {
-            match func() {
-                Ok(x) => return Ok(x),
-                Err(e) => {
+            // rspotify panics when a request fails at the transport level (connection errors or the
+            // HTTP client's 30 second timeout), so a hung request shows up here as a panic
+            match catch_quiet_unwind(&func) {
+                Ok(Ok(x)) => return Ok(x),
+                Ok(Err(e)) => {
+                    let transient = transient_error(&e).filter(|_| transient_failures < self.max_retries);
                     if let Some(ApiError::RateLimited(timeout)) = e.downcast_ref() {
-                        // TODO: better tracking of this
-                        eprintln!("Rate limited to {:?}", timeout);
-                        thread::sleep(Duration::from_secs(timeout.unwrap_or(1usize) as u64))
+                        if rate_limits >= MAX_RATE_LIMIT_RETRIES {
+                            return Err(e)
+                        }
+                        let delay = retry_delay(rate_limits, *timeout);
+                        rate_limits += 1;
+                        info!("Rate limited, retrying in {}s ({}/{})",
+                              delay.as_secs(),
+                              rate_limits,
+                              MAX_RATE_LIMIT_RETRIES);
+                        thread::sleep(delay)
+                    } else if let Some(reason) = transient {
+                        let delay = retry_delay(transient_failures, None);
+                        transient_failures += 1;
+                        info!("Request failed ({}), retrying in {}s ({}/{})",
+                              reason,
+                              delay.as_secs(),
+                              transient_failures,
+                              self.max_retries);
+                        thread::sleep(delay)
                     } else {
                         return Err(e)
                     }
                 }
+                Err(cause) => {
+                    if transient_failures >= self.max_retries {
+                        let reason = cause
+                            .downcast_ref::<String>()
+                            .map(|s| s.as_str())
+                            .or_else(|| cause.downcast_ref::<&str>().cloned())
+                            .unwrap_or("unknown error");
+                        return Err(failure::err_msg(format!("request failed at the transport level: {}",
+                                                            reason)))
+                    }
+                    let delay = retry_delay(transient_failures, None);
+                    transient_failures += 1;
+                    info!("Request failed, retrying in {}s ({}/{})",
+                          delay.as_secs(),
+                          transient_failures,
+                          self.max_retries);
+                    thread::sleep(delay)
+                }
             }
         }
     }
 
-    fn get_all<F, T>(get_page: F) -> Result<Vec<T>>
-        where F: Fn(u32) -> std::result::Result<Page<T>, failure::Error> {
-        let meta = Self::call_api(|| get_page(0))?;
-        let mut out = Vec::with_capacity(meta.total as usize);
+    /// Get the number of items to ask for in each page of an endpoint which allows up to `max`: the
+    /// `--page-size` if it was given (capped at `max`), otherwise `max`.
+    fn page_size(&self, max: u32) -> u32 {
+        self.page_size_override.map_or(max, |size| size.clamp(1, max))
+    }
+
+    /// Get every item of a paged endpoint. The first page says how many there are, then the rest are
+    /// fetched with up to `concurrency` requests at a time.
+    fn get_all<F, T>(&self, get_page: F) -> Result<Vec<T>>
+        where F: Fn(u32) -> std::result::Result<Page<T>, failure::Error> + Sync,
+              T: Send {
+        let first = self.call_api(|| get_page(0))?;
+        if first.items.is_empty() {
+            return Ok(first.items)
+        }
+        let page_size = first.limit.max(1) as usize;
+        let offsets: Vec<u32> = (first.items.len() as u32..first.total).step_by(page_size).collect();
+        debug!("Got {} of {} items, fetching {} more pages", first.items.len(), first.total, offsets.len());
+
+        let next_idx = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let pages = Mutex::new(Vec::with_capacity(offsets.len()));
+        thread::scope(|scope| {
+            for _ in 0..self.concurrency.clamp(1, offsets.len().max(1)) {
+                scope.spawn(|| {
+                    while !failed.load(Ordering::SeqCst) {
+                        let idx = next_idx.fetch_add(1, Ordering::SeqCst);
+                        let offset = match offsets.get(idx) {
+                            Some(offset) => *offset,
+                            None => break,
+                        };
+                        trace!("Fetching the page at offset={}", offset);
+                        let page = self.call_api(|| get_page(offset)).map(|page| page.items);
+                        if page.is_err() {
+                            failed.store(true, Ordering::SeqCst);
+                        }
+                        pages.lock().unwrap().push((idx, page));
+                    }
+                });
+            }
+        });
 
-        let mut offset = 0u32;
-        while offset < meta.total {
-            let mut res = Self::call_api(|| get_page(offset))?;
-            if res.items.is_empty() {
+        let mut pages = pages.into_inner().unwrap();
+        pages.sort_by_key(|(idx, _)| *idx);
+        let mut out = first.items;
+        out.reserve(first.total as usize);
+        for (idx, page) in pages {
+            let mut items = page?;
+            if items.is_empty() {
                 // This isn't really a problem -- the user might have altered the playlist since the
                 // initial request
-                eprintln!("Got 0 items in request for offset={}", offset);
+                warn!("Got 0 items in request for offset={}", offsets[idx]);
                 break
             }
-
-            offset += res.items.len() as u32;
-            out.append(&mut res.items);
+            out.append(&mut items);
         }
 
         Ok(out)
     }
 
     pub fn current_user_playlists(&self) -> Result<Vec<SimplifiedPlaylist>> {
-        Self::get_all(|off| self.native.current_user_playlists(None, off))
+        self.get_all(|off| self.native.current_user_playlists(self.page_size(50), off))
+    }
+
+    /// Get the public playlists owned by another user. Unlike `current_user_playlists`, this leaves
+    /// out the playlists they follow.
+    pub fn user_playlists(&self, user_id: &str) -> Result<Vec<SimplifiedPlaylist>> {
+        if let Err(e) = self.call_api(|| self.native.user(user_id)) {
+            return Err(match e.downcast_ref::<ApiError>() {
+                Some(ApiError::Other(400)) | Some(ApiError::Other(404)) => {
+                    SpotctlError::Api(format!("No Spotify user with the ID \"{}\"", user_id))
+                }
+                _ => e.into(),
+            })
+        }
+
+        Ok(self.get_all(|off| self.native.user_playlists(user_id, self.page_size(50), off))?
+            .into_iter()
+            .filter(|p| p.owner.id == user_id)
+            .collect())
+    }
+
+    /// Get the tracks saved in the user's library ("Liked Songs"), most recently saved first. They
+    /// are returned as playlist tracks, so they can go through the same grouping as playlists.
+    pub fn saved_tracks(&self) -> Result<Vec<PlaylistTrack>> {
+        Ok(self.get_all(|off| self.native.current_user_saved_tracks(self.page_size(50), off))?
+            .into_iter()
+            .map(|saved| PlaylistTrack {
+                added_at: saved.added_at,
+                added_by: None,
+                is_local: false,
+                track: saved.track,
+            })
+            .collect())
+    }
+
+    /// Get the albums saved in the user's library. Unlike the raw API response, the tracks of each
+    /// returned album are complete.
+    pub fn saved_albums(&self) -> Result<Vec<FullAlbum>> {
+        let mut albums: Vec<FullAlbum> =
+            self.get_all(|off| self.native.current_user_saved_albums(self.page_size(50), off))?
+            .into_iter()
+            .map(|saved| saved.album)
+            .collect();
+
+        for album in albums.iter_mut() {
+            if (album.tracks.items.len() as u32) < album.tracks.total {
+                let album_id = album.id.as_str();
+                album.tracks.items =
+                    self.get_all(|off| self.native.album_track(album_id, self.page_size(50), off))?;
+            }
+        }
+
+        Ok(albums)
     }
 
     /// Create a playlist with the given `name` and return the playlist ID.
-    pub fn create_playlist(&self, name: &str, description: Option<&str>) -> Result<String> {
+    pub fn create_playlist(&self, name: &str, description: Option<&str>, public: bool) -> Result<String> {
         let description = description.unwrap_or("Automatically-generated shuffled playlist");
 
-        Ok(Self::call_api(|| {
+        Ok(self.call_api(|| {
             self.native.user_playlist_create(self.user_id.as_str(),
                                              name,
-                                             false,
+                                             public,
                                              description.to_owned())
         })?.id)
     }
 
-    pub fn playlist_tracks(&self, playlist: &SimplifiedPlaylist) -> Result<Vec<PlaylistTrack>> {
-        Self::get_all(
+    /// Change the visibility or description of an existing playlist. Settings which are `None` are
+    /// left alone.
+    pub fn set_playlist_details(&self,
+                                playlist_id: &str,
+                                public: Option<bool>,
+                                collaborative: Option<bool>,
+                                description: Option<&str>)
+                                -> Result<()> {
+        self.call_api(|| {
+            self.native.user_playlist_change_detail(self.user_id.as_str(),
+                                                    playlist_id,
+                                                    None,
+                                                    public,
+                                                    description.map(|d| d.to_owned()),
+                                                    collaborative)
+        })?;
+        Ok(())
+    }
+
+    /// Send a request to an endpoint `rspotify` does not support. Errors are reported the same way
+    /// `rspotify` does, so this can be used with `call_api`.
+    fn send_raw(&self,
+                method: reqwest::Method,
+                path: &str,
+                content_type: &str,
+                body: String)
+                -> std::result::Result<(), failure::Error> {
+        let token = match self.native.client_credentials_manager.as_ref() {
+            Some(manager) => manager.get_access_token(),
+            None => return Err(failure::err_msg("Client has no credentials")),
+        };
+
+        let response = self.http
+            .request(method, format!("https://api.spotify.com/v1/{}", path).as_str())
+            .bearer_auth(token)
+            .header(reqwest::header::CONTENT_TYPE, content_type)
+            .body(body)
+            .send()?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(ApiError::from(&response).into())
+        }
+    }
+
+    /// Replace the playlist's cover image with `jpeg`, which must already be a valid JPEG.
+    pub fn set_playlist_cover(&self, playlist_id: &str, jpeg: &[u8]) -> Result<()> {
+        let path = format!("playlists/{}/images", playlist_id);
+        let body = base64::encode(jpeg);
+        self.call_api(|| self.send_raw(reqwest::Method::PUT, path.as_str(), "image/jpeg", body.clone()))?;
+        Ok(())
+    }
+
+    /// Add the track to the end of the playback queue on the given device, or the active device.
+    pub fn queue_track(&self, track_id: &str, device_id: Option<&str>) -> Result<()> {
+        let mut path = format!("me/player/queue?uri=spotify:track:{}", track_id);
+        if let Some(device_id) = device_id {
+            path += format!("&device_id={}", device_id).as_str();
+        }
+        self.call_api(||
+            self.send_raw(reqwest::Method::POST, path.as_str(), "application/json", String::new())
+        )?;
+        Ok(())
+    }
+
+    /// The devices the user's Spotify account can play on.
+    pub fn devices(&self) -> Result<Vec<Device>> {
+        Ok(self.call_api(|| self.native.device())?.devices)
+    }
+
+    /// Start playing the playlist from the beginning on the given device, or the active device.
+    pub fn play_playlist(&self, playlist_id: &str, device_id: Option<String>) -> Result<()> {
+        let context_uri = format!("spotify:playlist:{}", playlist_id);
+        self.call_api(|| self.native.start_playback(device_id.clone(),
+                                                     Some(context_uri.clone()),
+                                                     None,
+                                                     None,
+                                                     None))?;
+        Ok(())
+    }
+
+    /// Delete the playlist. Spotify playlists can't really be deleted, so this unfollows it.
+    pub fn delete_playlist(&self, playlist_id: &str) -> Result<()> {
+        self.call_api(|| self.native.user_playlist_unfollow(self.user_id.as_str(), playlist_id))?;
+        Ok(())
+    }
+
+    pub fn playlist_tracks(&self, playlist_id: &str) -> Result<Vec<PlaylistTrack>> {
+        self.playlist_tracks_in_market(playlist_id, None)
+    }
+
+    /// Get the tracks of the playlist as they are available in `market`. Tracks which can't be
+    /// played there have `is_playable` set to `Some(false)`, and tracks which are only available
+    /// as a different release are relinked, with the original in `linked_from`.
+    pub fn playlist_tracks_in_market(&self,
+                                     playlist_id: &str,
+                                     market: Option<Country>)
+                                     -> Result<Vec<PlaylistTrack>> {
+        self.user_playlist_tracks(self.user_id.as_str(), playlist_id, market)
+    }
+
+    /// Like `playlist_tracks_in_market`, for a playlist owned by `owner_id`. Another user's playlist
+    /// must be public.
+    pub fn user_playlist_tracks(&self,
+                                owner_id: &str,
+                                playlist_id: &str,
+                                market: Option<Country>)
+                                -> Result<Vec<PlaylistTrack>> {
+        self.get_all(
             |off| {
-                self.native.user_playlist_tracks(self.user_id.as_str(),
-                                                 playlist.id.as_str(),
-                                                 None,
-                                                 None,
+                self.native.user_playlist_tracks(owner_id,
+                                                 playlist_id,
+                                                 Some(Self::PLAYLIST_TRACK_FIELDS),
+                                                 self.page_size(100),
                                                  off,
-                                                 None)
+                                                 market.clone())
             })
     }
 
+    /// The country of the user's account, as an ISO 3166-1 alpha-2 code.
+    pub fn user_country(&self) -> Result<Option<String>> {
+        Ok(self.call_api(|| self.native.current_user())?.country)
+    }
+
+    /// Remove every occurrence of `track_ids` from the playlist.
+    pub fn remove_tracks(&self, playlist_id: &str, track_ids: &[String]) -> Result<()> {
+        for track_id_chunk in track_ids.chunks(100) {
+            self.call_api(||
+                self.native.user_playlist_remove_all_occurrences_of_tracks(self.user_id.as_str(),
+                                                                           playlist_id,
+                                                                           track_id_chunk,
+                                                                           None)
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Get the full artist objects for `artist_ids`, keyed by artist ID. They are looked up 50 at a
+    /// time, the most Spotify allows, and repeated IDs are only looked up once.
+    pub fn artists(&self, artist_ids: &[String]) -> Result<HashMap<String, FullArtist>> {
+        let artist_ids = unique_ids(artist_ids);
+        let mut out = HashMap::with_capacity(artist_ids.len());
+        for artist_id_chunk in artist_ids.chunks(50) {
+            let res = self.call_api(|| self.native.artists(artist_id_chunk.to_vec()))?;
+            for artist in res.artists {
+                out.insert(artist.id.clone(), artist);
+            }
+        }
+        Ok(out)
+    }
+
+    /// Get the full album objects for `album_ids`, keyed by album ID. Like `artists`, they are looked
+    /// up 20 at a time and repeated IDs are only looked up once. The tracks of long albums are cut
+    /// short, but their `total` is right.
+    pub fn albums(&self, album_ids: &[String]) -> Result<HashMap<String, FullAlbum>> {
+        let album_ids = unique_ids(album_ids);
+        let mut out = HashMap::with_capacity(album_ids.len());
+        for album_id_chunk in album_ids.chunks(20) {
+            let res = self.call_api(|| self.native.albums(album_id_chunk.to_vec()))?;
+            for album in res.albums {
+                out.insert(album.id.clone(), album);
+            }
+        }
+        Ok(out)
+    }
+
     pub fn set_playlist(&self, playlist_id: &str, track_ids: &[String]) -> Result<()> {
         // Clear the playlist
-        Self::call_api(||
+        self.call_api(||
             self.native.user_playlist_replace_tracks(self.user_id.as_str(), playlist_id, &[])
         )?;
 
-        for track_id_chunk in track_ids.chunks(100) {
-            Self::call_api(||
-                self.native.user_playlist_add_tracks(self.user_id.as_str(), playlist_id, track_id_chunk, None)
+        self.add_tracks(playlist_id, track_ids, None)
+    }
+
+    /// Add `track_ids` to the playlist without removing anything. They are inserted starting at
+    /// `position` or at the end of the playlist if it is `None`.
+    pub fn add_tracks(&self, playlist_id: &str, track_ids: &[String], position: Option<usize>) -> Result<()> {
+        for (chunk_idx, track_id_chunk) in track_ids.chunks(100).enumerate() {
+            let chunk_position = position.map(|pos| (pos + chunk_idx * 100) as i32);
+            self.call_api(||
+                self.native.user_playlist_add_tracks(self.user_id.as_str(),
+                                                     playlist_id,
+                                                     track_id_chunk,
+                                                     chunk_position)
             )?;
         }
         Ok(())
     }
+
+    /// Reorder the tracks of `playlist_id` from the `current` order into the `target` order without
+    /// removing anything. The two must contain the same tracks. Returns the number of moves made.
+    pub fn reorder_playlist(&self,
+                            playlist_id: &str,
+                            mut current: Vec<String>,
+                            target: &[String])
+                            -> Result<usize> {
+        let mut moves = 0;
+        for (idx, track_id) in target.iter().enumerate() {
+            if current[idx] == *track_id {
+                continue
+            }
+
+            let from = idx + current[idx..]
+                .iter()
+                .position(|t| t == track_id)
+                .expect("target must be a permutation of current");
+            self.call_api(|| {
+                self.native.user_playlist_recorder_tracks(self.user_id.as_str(),
+                                                          playlist_id,
+                                                          from as i32,
+                                                          1u32,
+                                                          idx as i32,
+                                                          None)
+            })?;
+
+            let moved = current.remove(from);
+            current.insert(idx, moved);
+            moves += 1;
+        }
+        Ok(moves)
+    }
+}
+
+/// Get `ids` without repeats, in the order they first appear.
+fn unique_ids(ids: &[String]) -> Vec<String> {
+    let mut seen = HashSet::with_capacity(ids.len());
+    ids.iter().filter(|id| seen.insert(id.as_str())).cloned().collect()
 }
 
 /// A group of tracks. This generally represents an album, but can be any grouped unit that one
@@ -147,141 +756,2798 @@ impl Client {
 struct TrackGroup {
     /// An arbitrary name to give this group (usually the album name).
     pub name: String,
-    pub track_ids: Vec<String>,
+    /// The Spotify album this group was taken from, if it represents a single album.
+    pub album_id: Option<String>,
+    /// The primary artists of the group (the artists of the first track).
+    pub artists: Vec<SimplifiedArtist>,
+    pub tracks: Vec<Track>,
     /// Total length of time of all tracks in this group. It is the responsibility of the creation
     /// function to ensure this is correct.
     pub duration: Duration,
 }
 
+/// A single track in a `TrackGroup`.
+#[derive(Clone, Debug)]
+struct Track {
+    pub id: String,
+    pub name: String,
+    /// The names of the track's artists.
+    pub artists: Vec<String>,
+    pub duration: Duration,
+    pub explicit: bool,
+}
+
 impl From<&[PlaylistTrack]> for TrackGroup {
     fn from(src: &[PlaylistTrack]) -> Self {
         assert!(!src.is_empty());
 
         TrackGroup{
             name: src[0].track.album.name.to_owned(),
-            track_ids: Vec::from_iter(src.iter().map(|t| t.track.id.as_ref().unwrap().to_owned())),
-            duration: src
+            album_id: src[0].track.album.id.clone(),
+            artists: src[0].track.artists.clone(),
+            tracks: Vec::from_iter(src.iter().filter_map(|t| Some(Track {
+                id: t.track.id.clone()?,
+                name: t.track.name.clone(),
+                artists: t.track.artists.iter().map(|a| a.name.clone()).collect(),
+                duration: Duration::from_millis(t.track.duration_ms as u64),
+                explicit: t.track.explicit,
+            }))),
+            duration: total_duration(src.iter().filter(|t| t.track.id.is_some())),
+        }
+    }
+}
+
+impl From<&FullAlbum> for TrackGroup {
+    fn from(src: &FullAlbum) -> Self {
+        TrackGroup{
+            name: src.name.to_owned(),
+            album_id: Some(src.id.to_owned()),
+            artists: src.artists.clone(),
+            tracks: src.tracks.items
+                .iter()
+                .filter_map(|t| Some(Track {
+                    id: t.id.clone()?,
+                    name: t.name.clone(),
+                    artists: t.artists.iter().map(|a| a.name.clone()).collect(),
+                    duration: Duration::from_millis(t.duration_ms as u64),
+                    explicit: t.explicit,
+                }))
+                .collect(),
+            duration: src.tracks.items
                 .iter()
                 .fold(Duration::new(0, 0),
-                      |acc, x| acc + Duration::from_millis(x.track.duration_ms as u64))
+                      |acc, x| acc + Duration::from_millis(x.duration_ms as u64))
         }
     }
 }
 
-fn partition_by_album(mut src_tracks: &[PlaylistTrack]) -> Vec<TrackGroup> {
+/// Split `src_tracks` into runs of consecutive tracks, starting a new run between any two tracks
+/// where `is_split(previous, next)` is true.
+fn split_runs<F>(mut src_tracks: &[PlaylistTrack], is_split: F) -> Vec<&[PlaylistTrack]>
+    where F: Fn(&PlaylistTrack, &PlaylistTrack) -> bool {
     let mut out = Vec::new();
 
     while !src_tracks.is_empty() {
-        assert!(src_tracks[0].track.album.id.is_some());
         let split_idx = src_tracks
-            .iter()
-            .position(|t| t.track.album.id != src_tracks[0].track.album.id)
-            .unwrap_or(src_tracks.len());
+            .windows(2)
+            .position(|pair| is_split(&pair[0], &pair[1]))
+            .map_or(src_tracks.len(), |idx| idx + 1);
 
         let (next_tracks, remaining) = src_tracks.split_at(split_idx);
         src_tracks = remaining;
+        out.push(next_tracks);
+    }
 
-        let group = TrackGroup::from(next_tracks);
-        // If the group is less than 10 minutes, it isn't an album
-        if group.duration > Duration::from_secs(600u64) {
-            out.push(group);
+    out
+}
+
+/// Album IDs which should be treated as the same album, such as the standard and deluxe editions, as
+/// given by `--album-aliases`.
+#[derive(Default)]
+struct AlbumAliases {
+    /// The first album ID of each alias set, keyed by the others.
+    canonical: HashMap<String, String>,
+}
+
+impl AlbumAliases {
+    /// Load aliases from a JSON file containing a list of alias sets, each a list of album IDs (or
+    /// URIs or links).
+    pub fn load(path: &Path) -> Result<AlbumAliases> {
+        let file = File::open(path).map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+        let sets: Vec<Vec<String>> = serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| SpotctlError::Parse(format!("Could not parse {}: {}", path.display(), e)))?;
+        Self::from_sets(sets)
+    }
+
+    /// Build aliases from sets of album IDs. The first ID of each set is used for the merged group.
+    pub fn from_sets(sets: Vec<Vec<String>>) -> Result<AlbumAliases> {
+        let mut canonical: HashMap<String, String> = HashMap::new();
+        for set in sets {
+            let ids = set
+                .iter()
+                .map(|id| cmd::parse_spotify_id(cmd::SpotifyKind::Album, id.as_str()))
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            let first = match ids.first() {
+                Some(first) => first.clone(),
+                None => continue,
+            };
+            for id in ids {
+                match canonical.get(&id) {
+                    Some(existing) if *existing != first => {
+                        return Err(format!("Album {} is in more than one alias set", id).into())
+                    }
+                    _ => { canonical.insert(id, first.clone()); }
+                }
+            }
         }
+        Ok(AlbumAliases { canonical })
     }
 
-    out
+    /// The ID the album is known by after merging aliases.
+    pub fn canonical<'a>(&'a self, album_id: &'a str) -> &'a str {
+        self.canonical.get(album_id).map_or(album_id, |id| id.as_str())
+    }
 }
 
-fn partition_groups(playlist_name: &str, src_tracks: &[PlaylistTrack]) -> Vec<TrackGroup> {
-    let duration = src_tracks
-        .iter()
+fn partition_by_album(src_tracks: &[PlaylistTrack],
+                      aliases: &AlbumAliases,
+                      min_album_length: Duration)
+                      -> Vec<TrackGroup> {
+    assert!(src_tracks.iter().all(|t| t.track.album.id.is_some()));
+
+    fn canonical_album_id<'a>(aliases: &'a AlbumAliases, track: &'a PlaylistTrack) -> &'a str {
+        aliases.canonical(track.track.album.id.as_ref().unwrap().as_str())
+    }
+
+    split_runs(src_tracks,
+               |prev, next| canonical_album_id(aliases, prev) != canonical_album_id(aliases, next))
+        .into_iter()
+        .map(|tracks| {
+            let mut group = TrackGroup::from(tracks);
+            group.album_id = Some(canonical_album_id(aliases, &tracks[0]).to_owned());
+            group
+        })
+        // If the group is too short, it isn't an album
+        .filter(|group| group.duration > min_album_length)
+        .collect()
+}
+
+/// Split `src_tracks` into listening sessions: tracks which were added to the playlist less than
+/// `gap` apart. Each group is named after the dates it was added, and sessions no longer than
+/// `min_length` are dropped.
+fn partition_by_session(src_tracks: &[PlaylistTrack],
+                        gap: Duration,
+                        min_length: Duration)
+                        -> Vec<TrackGroup> {
+    let mut src_tracks = src_tracks.to_vec();
+    src_tracks.sort_by_key(|t| t.added_at);
+
+    split_runs(src_tracks.as_slice(),
+               |prev, next| (next.added_at - prev.added_at).to_std().is_ok_and(|d| d > gap))
+        .into_iter()
+        .map(|tracks| {
+            let first = tracks[0].added_at.format("%Y-%m-%d").to_string();
+            let last = tracks[tracks.len() - 1].added_at.format("%Y-%m-%d").to_string();
+
+            let mut group = TrackGroup::from(tracks);
+            group.name = if first == last {
+                format!("Added {}", first)
+            } else {
+                format!("Added {} to {}", first, last)
+            };
+            group.album_id = None;
+            group
+        })
+        // Like albums, a session shorter than `--min-album-length` isn't worth keeping together
+        .filter(|group| group.duration > min_length)
+        .collect()
+}
+
+/// The total play time of the tracks.
+fn total_duration<'a, I>(tracks: I) -> Duration
+    where I: IntoIterator<Item = &'a PlaylistTrack> {
+    tracks
+        .into_iter()
         .fold(Duration::new(0, 0),
-              |acc, x| acc + Duration::from_millis(x.track.duration_ms as u64));
+              |acc, x| acc + Duration::from_millis(x.track.duration_ms as u64))
+}
+
+/// Split `src_tracks` into groups according to `--group-by`. With `--playlist-as-album`, a playlist
+/// between 45 and 90 minutes long is treated as a single group instead. Groups longer than
+/// `--max-album-duration` are dropped.
+fn partition_groups(playlist_name: &str,
+                    src_tracks: &[PlaylistTrack],
+                    aliases: &AlbumAliases,
+                    opts: &cmd::GroupOpts)
+                    -> Vec<TrackGroup> {
+    // Local files and tracks which are no longer available have no ID, so they can't be added to the
+    // output playlist and shouldn't count toward any durations
+    let (src_tracks, missing): (Vec<PlaylistTrack>, Vec<PlaylistTrack>) =
+        src_tracks.iter().cloned().partition(|t| t.track.id.is_some());
+    for track in missing {
+        warn!("Skipping \"{}\" in {} (local file or unavailable track)",
+              track.track.name,
+              playlist_name);
+    }
+
+    // With a market, tracks which can't be played there are marked, unless Spotify relinked them to
+    // a release which can
+    let (src_tracks, unavailable): (Vec<PlaylistTrack>, Vec<PlaylistTrack>) = src_tracks
+        .into_iter()
+        .partition(|t| opts.include_unavailable || t.track.is_playable != Some(false));
+    if !unavailable.is_empty() {
+        info!(" ! Skipping {} tracks in {} which can't be played in the market",
+              unavailable.len(),
+              playlist_name);
+    }
+    let src_tracks = src_tracks.as_slice();
+
+    let duration = total_duration(src_tracks);
 
-    if duration > Duration::from_secs(60u64 * 45) && duration < Duration::from_secs(60u64 * 90) {
+    let single_group = opts.playlist_as_album
+        && duration > opts.playlist_as_album_min
+        && duration < opts.playlist_as_album_max;
+    let groups = if single_group {
         let mut group = TrackGroup::from(src_tracks);
         group.name = playlist_name.to_string();
+        group.album_id = None;
         vec![group]
     } else {
-        partition_by_album(src_tracks)
-    }
+        match opts.group_by {
+            cmd::GroupBy::Album => partition_by_album(src_tracks, aliases, opts.min_album_length),
+            cmd::GroupBy::Session => {
+                partition_by_session(src_tracks, opts.session_gap, opts.min_album_length)
+            }
+        }
+    };
+
+    drop_long_groups(groups, opts.max_album_duration)
 }
 
-fn load_groups(client: &Client) -> Result<Vec<TrackGroup>> {
-    let banned_playlist_names: HashSet<&str> =
-        ["Discover Weekly", "Starred", "Liked from Radio", "Shuffle"].iter().cloned().collect();
+/// Remove the groups longer than `max_album_duration`.
+fn drop_long_groups(groups: Vec<TrackGroup>, max_album_duration: Option<Duration>) -> Vec<TrackGroup> {
+    match max_album_duration {
+        Some(max) => groups
+            .into_iter()
+            .filter(|group| {
+                if group.duration > max {
+                    info!(" - {} ({:.1} hours is too long)",
+                          group.name,
+                          group.duration.as_secs_f64() / 3600.0);
+                    false
+                } else {
+                    true
+                }
+            })
+            .collect(),
+        None => groups,
+    }
+}
 
-    let mut groups = Vec::new();
-    for playlist in client.current_user_playlists()? {
-        if banned_playlist_names.contains(playlist.name.as_str()) {
-            continue
+/// Get the playlists in the user's library, or the public playlists of the `--from-user`.
+fn library_playlists(client: &Client, opts: &cmd::SourceOpts) -> Result<Vec<SimplifiedPlaylist>> {
+    match opts.from_user.as_ref() {
+        Some(user_id) => {
+            let playlists = client.user_playlists(user_id.as_str())?;
+            if playlists.is_empty() {
+                return Err(format!("User \"{}\" has no public playlists", user_id).into())
+            }
+            Ok(playlists)
         }
+        None => client.current_user_playlists(),
+    }
+}
 
-        let tracks = client.playlist_tracks(&playlist)?;
-        let mut pl_groups = partition_groups(playlist.name.as_str(), tracks.as_slice());
-        groups.append(&mut pl_groups);
+/// Get the playlists in the user's library (or the `--from-user`'s) which feed the shuffle. Without
+/// any `--source`, this is every playlist `exclusion_reason` gives no reason to skip.
+fn source_playlists<F>(client: &Client,
+                       opts: &cmd::SourceOpts,
+                       exclusion_reason: F)
+                       -> Result<Vec<SimplifiedPlaylist>>
+    where F: Fn(&SimplifiedPlaylist) -> Option<&'static str> {
+    let playlists = library_playlists(client, opts)?;
+    if opts.sources.is_empty() {
+        return Ok(playlists.into_iter().filter(|p| exclusion_reason(p).is_none()).collect())
     }
 
-    Ok(groups)
+    // Explicit sources are used in the order they were given, even ones which would be excluded
+    let mut out: Vec<SimplifiedPlaylist> = Vec::new();
+    for source in opts.sources.iter() {
+        match playlists.iter().find(|p| is_source(p, source.as_str())) {
+            Some(playlist) => {
+                if !out.iter().any(|p| p.id == playlist.id) {
+                    out.push(playlist.clone());
+                }
+            }
+            None => {
+                // Only public playlists of other users can be read, so say that's why it wasn't found
+                let missing = match opts.from_user.as_ref() {
+                    Some(user_id) => format!("No public playlist named \"{}\" owned by {}", source, user_id),
+                    None => format!("No playlist named \"{}\"", source),
+                };
+                return Err(suggest_playlists(missing, source, playlists.as_slice()).into())
+            }
+        }
+    }
+    Ok(out)
 }
 
-/// Create a playlist from `src`.
-///
-/// Returns a list of track IDs.
-fn create_playlist(mut src: Vec<TrackGroup>, goal_duration: Option<Duration>) -> Vec<String> {
-    let goal_duration = goal_duration.unwrap_or(Duration::from_secs(60u64 * 1200));
+/// Add the names of the `playlists` closest to `name`, if any, to the `missing` message about it.
+fn suggest_playlists(missing: String, name: &str, playlists: &[SimplifiedPlaylist]) -> String {
+    let suggestions = fuzzy::closest_matches(name, playlists.iter().map(|p| p.name.as_str()));
+    match suggestions.as_slice() {
+        [] => missing,
+        _ => format!("{} -- did you mean \"{}\"?", missing, suggestions.join("\", \"")),
+    }
+}
 
-    let mut rng = thread_rng();
-    src.shuffle(&mut rng);
+/// Check if `playlist` is the one referred to by `source`, a name, ID, URI or URL.
+fn is_source(playlist: &SimplifiedPlaylist, source: &str) -> bool {
+    playlist.name == source
+        || cmd::parse_spotify_id(cmd::SpotifyKind::Playlist, source).is_ok_and(|id| playlist.id == id)
+}
 
-    let mut playlist_duration = Duration::new(0, 0);
-    let mut out = Vec::new();
-    for group in src.iter() {
-        if playlist_duration > goal_duration {
-            break
-        }
+/// Get why `playlist` is not used as a source of track groups, or `None` if it is.
+fn exclusion_reason(playlist: &SimplifiedPlaylist, opts: &cmd::SourceOpts) -> Option<&'static str> {
+    let name = playlist.name.as_str();
+    if !opts.sources.is_empty() {
+        return if opts.sources.iter().any(|s| is_source(playlist, s)) { None } else { Some("not a --source") }
+    }
+    if BANNED_PLAYLIST_NAMES.contains(&name) {
+        Some("generated by Spotify")
+    } else if opts.from_user.is_none() && name == opts.output {
+        Some("output playlist")
+    } else if opts.exclude_playlists.iter().any(|excluded| excluded == name) {
+        Some("--exclude")
+    } else if opts.exclude_playlist_patterns.iter().any(|re| re.is_match(name)) {
+        Some("--exclude-regex")
+    } else {
+        None
+    }
+}
 
-        eprintln!(" + {}", group.name);
-        out.reserve(group.track_ids.len());
-        for id in group.track_ids.iter() {
-            out.push(id.clone());
+/// Get why `playlist` is not used as a source by `shuffle-library`, which also skips the playlists it
+/// writes itself with `--split-size` and `--rotate`.
+fn shuffle_exclusion_reason(playlist: &SimplifiedPlaylist,
+                            opts: &cmd::ShuffleLibraryOpts)
+                            -> Option<&'static str> {
+    let source = &opts.groups.source;
+    let name = playlist.name.as_str();
+    exclusion_reason(playlist, source).or_else(|| {
+        if !source.sources.is_empty() {
+            None
+        } else if source.from_user.is_none()
+            && opts.split_size.is_some()
+            && is_split_part(name, &source.output) {
+            Some("output playlist")
+        } else if opts.rotate.is_some() && rotation_date(opts.name_template.as_str(), name).is_some() {
+            Some("rotated shuffle")
+        } else {
+            None
         }
-        playlist_duration += group.duration;
-    }
+    })
+}
 
-    eprintln!("Play time: {} hours", playlist_duration.as_secs_f64() / 3600.0);
-    out
+/// Let the user pick which of `playlists` to shuffle and the target duration from a terminal menu.
+fn select_interactively(playlists: Vec<SimplifiedPlaylist>)
+                        -> Result<(Vec<SimplifiedPlaylist>, Option<Duration>)> {
+    let names: Vec<&str> = playlists.iter().map(|p| p.name.as_str()).collect();
+    let selected = MultiSelect::new()
+        .with_prompt("Playlists to shuffle (space to toggle, enter to confirm)")
+        .items(names.as_slice())
+        .defaults(vec![true; names.len()].as_slice())
+        .interact()?;
+
+    let duration: String = Input::new()
+        .with_prompt("Target duration")
+        .default("20h".to_owned())
+        .validate_with(|input: &String| cmd::parse_duration(input).map(|_| ()))
+        .interact_text()?;
+    let duration = cmd::parse_duration(duration.as_str())?;
+
+    let selected: HashSet<usize> = selected.into_iter().collect();
+    let playlists = playlists
+        .into_iter()
+        .enumerate()
+        .filter(|(idx, _)| selected.contains(idx))
+        .map(|(_, p)| p)
+        .collect();
+    Ok((playlists, Some(duration)))
 }
 
-/// Get the playlist ID for the shuffle output. This will either create a new playlist named
-/// `"Shuffle"` or pick the playlist with that name.
-fn get_or_create_shuffle_playlist_id(client: &Client) -> Result<String> {
-    let name = "Shuffle";
+/// A playlist loaded by an earlier, interrupted run of `shuffle-library`.
+#[derive(Serialize, Deserialize)]
+struct LoadedPlaylist {
+    pub id: String,
+    pub snapshot_id: String,
+    pub tracks: Vec<PlaylistTrack>,
+}
 
-    for playlist in client.current_user_playlists()? {
-        if playlist.name.as_str() == name {
-            eprintln!("Reusing existing playlist with ID={}", playlist.id);
-            return Ok(playlist.id)
+/// A playlist in the `LibraryCache`.
+#[derive(Serialize, Deserialize)]
+struct CachedPlaylist {
+    pub snapshot_id: String,
+    /// When the tracks were fetched, in seconds since the Unix epoch.
+    pub fetched_at: u64,
+    pub tracks: Vec<PlaylistTrack>,
+}
+
+/// The tracks of the playlists loaded by recent runs, so shuffling again soon after doesn't have to
+/// fetch the whole library.
+#[derive(Default, Serialize, Deserialize)]
+struct LibraryCache {
+    /// The user the playlists were loaded for.
+    pub user_id: String,
+    /// The market the playlists were loaded in, which decides the tracks' availability.
+    pub market: Option<String>,
+    /// Cached playlists by ID.
+    pub playlists: HashMap<String, CachedPlaylist>,
+}
+
+impl LibraryCache {
+    /// Load the cache for `user_id` in `market`. A missing or unreadable cache, or one for another
+    /// user or market, is the same as an empty one.
+    pub fn load(config_dir: &Path, user_id: &str, market: Option<&str>) -> LibraryCache {
+        File::open(config_dir.join(LIBRARY_CACHE_FILE))
+            .ok()
+            .and_then(|file| serde_json::from_reader::<_, LibraryCache>(BufReader::new(file)).ok())
+            .filter(|cache| cache.user_id == user_id && cache.market.as_deref() == market)
+            .unwrap_or_else(|| LibraryCache {
+                user_id: user_id.to_owned(),
+                market: market.map(|m| m.to_owned()),
+                playlists: HashMap::new(),
+            })
+    }
+
+    /// The cached tracks of the playlist, unless it has changed since or they are older than `ttl`.
+    pub fn get(&self, playlist: &SimplifiedPlaylist, ttl: Duration) -> Option<Vec<PlaylistTrack>> {
+        self.playlists
+            .get(&playlist.id)
+            .filter(|p| p.snapshot_id == playlist.snapshot_id)
+            .filter(|p| unix_time().saturating_sub(p.fetched_at) < ttl.as_secs())
+            .map(|p| p.tracks.clone())
+    }
+
+    pub fn insert(&mut self, playlist: &SimplifiedPlaylist, tracks: Vec<PlaylistTrack>) {
+        let cached = CachedPlaylist {
+            snapshot_id: playlist.snapshot_id.clone(),
+            fetched_at: unix_time(),
+            tracks,
+        };
+        self.playlists.insert(playlist.id.clone(), cached);
+    }
+
+    /// Write the cache back. Like `save_last_shuffle`, failing to do this is not fatal.
+    pub fn save(&self, config_dir: &Path) {
+        let path = config_dir.join(LIBRARY_CACHE_FILE);
+        let res = File::create(&path)
+            .map_err(SpotctlError::from)
+            .and_then(|file| Ok(serde_json::to_writer(BufWriter::new(file), self)?));
+        if let Err(e) = res {
+            warn!("Could not cache the library in {}: {}", path.display(), e);
         }
     }
+}
+
+/// The current time in seconds since the Unix epoch.
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
 
-    client.create_playlist(name, None)
+/// Records the tracks of each playlist as it is loaded, one JSON object per line, so a run which is
+/// interrupted part way can pick up where it left off with `--resume`.
+struct ResumeLog {
+    path: PathBuf,
+    loaded: HashMap<String, LoadedPlaylist>,
+    out: Option<BufWriter<File>>,
 }
 
-fn shuffle_library() -> Result<()> {
-    let client = Client::new()?;
-    let groups = load_groups(&client)?;
+impl ResumeLog {
+    /// Start recording loaded playlists in `file` in the config directory. If `resume` is set,
+    /// playlists recorded by the previous run are kept; otherwise the log starts out empty.
+    pub fn open(config_dir: &Path, file: &str, resume: bool) -> ResumeLog {
+        let path = config_dir.join(file);
+
+        let mut loaded = HashMap::new();
+        if resume {
+            if let Ok(file) = File::open(&path) {
+                // The last line is probably cut short if the previous run was interrupted
+                for line in BufReader::new(file).lines().map_while(|l| l.ok()) {
+                    if let Ok(playlist) = serde_json::from_str::<LoadedPlaylist>(line.as_str()) {
+                        loaded.insert(playlist.id.clone(), playlist);
+                    }
+                }
+            }
+            info!("Resuming with {} playlists already loaded", loaded.len());
+        }
+
+        let out = fs::OpenOptions::new()
+            .create(true)
+            .append(resume)
+            .write(true)
+            .truncate(!resume)
+            .open(&path)
+            .map(BufWriter::new)
+            .map_err(|e| warn!("Can't record progress in {}: {}", path.display(), e))
+            .ok();
+
+        ResumeLog { path, loaded, out }
+    }
+
+    /// The tracks of the playlist from the previous run, unless the playlist has changed since.
+    pub fn take(&mut self, playlist: &SimplifiedPlaylist) -> Option<Vec<PlaylistTrack>> {
+        self.loaded
+            .remove(&playlist.id)
+            .filter(|p| p.snapshot_id == playlist.snapshot_id)
+            .map(|p| p.tracks)
+    }
 
-    let track_ids = create_playlist(groups, None);
-    let playlist_id = get_or_create_shuffle_playlist_id(&client)?;
+    pub fn record(&mut self, playlist: &SimplifiedPlaylist, tracks: Vec<PlaylistTrack>) {
+        if let Some(out) = self.out.as_mut() {
+            let loaded = LoadedPlaylist {
+                id: playlist.id.clone(),
+                snapshot_id: playlist.snapshot_id.clone(),
+                tracks,
+            };
+            let res = serde_json::to_writer(&mut *out, &loaded)
+                .map_err(io::Error::from)
+                .and_then(|_| writeln!(out))
+                .and_then(|_| out.flush());
+            if let Err(e) = res {
+                warn!("Can't record progress in {}: {}", self.path.display(), e);
+                self.out = None;
+            }
+        }
+    }
 
-    client.set_playlist(playlist_id.as_str(), track_ids.as_slice())
+    /// Remove the log once the run has finished successfully.
+    pub fn finish(self) {
+        drop(self.out);
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Could not remove {}: {}", self.path.display(), e);
+        }
+    }
 }
 
-fn main() -> Result<()> {
-    use cmd::BaseCmd::*;
+fn load_groups(client: &Client,
+               playlists: &[SimplifiedPlaylist],
+               aliases: &AlbumAliases,
+               resume_log: &mut ResumeLog,
+               opts: &cmd::GroupOpts)
+               -> Result<Vec<TrackGroup>> {
+    let market_code = match opts.market.clone() {
+        Some(code) => Some(code),
+        None => client.user_country()?,
+    };
+    let market = match market_code.as_deref() {
+        Some(code) => match Country::from_str(code) {
+            Some(market) => Some(market),
+            None if opts.market.is_some() => {
+                return Err(SpotctlError::Parse(format!("Unknown market \"{}\"", code)))
+            }
+            None => None,
+        },
+        None => None,
+    };
+    if market.is_none() {
+        warn!("Account has no known country -- not checking whether tracks are available");
+    }
 
-    let opts = cmd::BaseOpts::from_args();
+    let mut cache = LibraryCache::load(&client.config_dir,
+                                       client.user_id.as_str(),
+                                       market.as_ref().and(market_code.as_deref()));
+    if opts.refresh {
+        cache.playlists.clear();
+    }
+
+    let mut groups = Vec::new();
+    let mut failed_playlists = Vec::new();
+    let mut cached_count = 0usize;
+    for (idx, playlist) in playlists.iter().enumerate() {
+        let cached = resume_log.take(playlist).or_else(|| cache.get(playlist, opts.cache_ttl));
+        if cached.is_some() {
+            cached_count += 1;
+        } else {
+            info!("Fetching playlist {}/{}: {}", idx + 1, playlists.len(), playlist.name);
+        }
+        let tracks = match cached {
+            Some(tracks) => tracks,
+            None => match client.user_playlist_tracks(playlist.owner.id.as_str(),
+                                                      playlist.id.as_str(),
+                                                      market.clone()) {
+                Ok(tracks) => {
+                    resume_log.record(playlist, tracks.clone());
+                    cache.insert(playlist, tracks.clone());
+                    tracks
+                }
+                Err(e) if opts.continue_on_error => {
+                    info!(" ! {}: {}", playlist.name, e);
+                    failed_playlists.push(format!("{} ({})", playlist.name, e));
+                    continue
+                }
+                Err(e) => return Err(e),
+            },
+        };
+
+        if let Some(min) = opts.min_playlist_duration {
+            let duration = total_duration(tracks.as_slice());
+            if duration < min {
+                info!(" - {} ({} minutes is too short)", playlist.name, duration.as_secs() / 60);
+                continue
+            }
+        }
+
+        let mut pl_groups = partition_groups(playlist.name.as_str(), tracks.as_slice(), aliases, opts);
+        groups.append(&mut pl_groups);
+    }
+    cache.save(&client.config_dir);
+    if cached_count > 0 {
+        info!("Loaded {} unchanged playlists from the cache", cached_count);
+    }
+    if !failed_playlists.is_empty() {
+        info!("Skipped {} playlists which could not be loaded:", failed_playlists.len());
+        for failed in failed_playlists.iter() {
+            info!(" - {}", failed);
+        }
+    }
+
+    if opts.include_liked {
+        info!("Fetching liked songs");
+        // Liked songs are in the order they were saved, so put the tracks of each album together
+        let mut tracks = client.saved_tracks()?;
+        tracks.sort_by(|a, b| {
+            (a.track.album.id.as_ref(), a.track.disc_number, a.track.track_number)
+                .cmp(&(b.track.album.id.as_ref(), b.track.disc_number, b.track.track_number))
+        });
+
+        // As with saved albums, prefer the playlist version of an album
+        let seen_album_ids: HashSet<String> = groups.iter().filter_map(|g| g.album_id.clone()).collect();
+        let mut liked_groups: Vec<TrackGroup> =
+            partition_groups("Liked Songs", tracks.as_slice(), aliases, opts)
+            .into_iter()
+            .filter(|g| g.album_id.as_ref().is_none_or(|id| !seen_album_ids.contains(id)))
+            .collect();
+        info!("Loaded {} track groups from liked songs", liked_groups.len());
+        groups.append(&mut liked_groups);
+    }
+
+    if opts.include_saved_albums {
+        // Prefer the playlist version of an album, since the user might have curated its order
+        let seen_album_ids: HashSet<String> = groups.iter().filter_map(|g| g.album_id.clone()).collect();
+        let mut duplicates = 0usize;
+        let mut saved_groups = Vec::new();
+        info!("Fetching saved albums");
+        for album in client.saved_albums()? {
+            if seen_album_ids.contains(aliases.canonical(album.id.as_str())) {
+                duplicates += 1;
+            } else {
+                saved_groups.push(TrackGroup::from(&album));
+            }
+        }
+
+        info!("Loaded {} saved albums ({} already in playlists)",
+              saved_groups.len(),
+              duplicates);
+        groups.append(&mut drop_long_groups(saved_groups, opts.max_album_duration));
+    }
+
+    Ok(groups)
+}
+
+/// Look up the genres of every artist in `groups`. The result is keyed by artist ID and the genres
+/// are lowercased for matching.
+fn load_artist_genres(client: &Client, groups: &[TrackGroup]) -> Result<HashMap<String, Vec<String>>> {
+    let artist_ids: Vec<String> = groups
+        .iter()
+        .flat_map(|g| g.artists.iter())
+        .filter_map(|a| a.id.clone())
+        .collect();
+
+    Ok(client
+        .artists(artist_ids.as_slice())?
+        .into_iter()
+        .map(|(id, artist)| (id, artist.genres.iter().map(|g| g.to_lowercase()).collect()))
+        .collect())
+}
+
+/// Remove tracks according to `--explicit-filter`, dropping groups which end up empty.
+fn filter_explicit(groups: Vec<TrackGroup>, opts: &cmd::GroupOpts) -> Vec<TrackGroup> {
+    let keep_explicit = match opts.explicit_filter {
+        cmd::ExplicitFilter::All => return groups,
+        cmd::ExplicitFilter::Off => false,
+        cmd::ExplicitFilter::Only => true,
+    };
+
+    let mut removed = 0usize;
+    let mut out = Vec::with_capacity(groups.len());
+    for mut group in groups {
+        let original_len = group.tracks.len();
+        group.tracks.retain(|t| t.explicit == keep_explicit);
+        removed += original_len - group.tracks.len();
+
+        if group.tracks.is_empty() {
+            info!(" - {} (no tracks left after filtering explicit tracks)", group.name);
+        } else {
+            group.duration = group.tracks.iter().map(|t| t.duration).sum();
+            out.push(group);
+        }
+    }
+
+    info!("Filtered out {} {} tracks", removed, if keep_explicit { "non-explicit" } else { "explicit" });
+    out
+}
+
+/// Keep only the track groups which contain the track given with `--contains-track`, if any.
+fn filter_by_track(groups: Vec<TrackGroup>, opts: &cmd::GroupOpts) -> Result<Vec<TrackGroup>> {
+    let track_id = match opts.contains_track.as_ref() {
+        Some(track_id) => track_id,
+        None => return Ok(groups),
+    };
+
+    let matching: Vec<TrackGroup> = groups
+        .into_iter()
+        .filter(|g| g.tracks.iter().any(|t| t.id == *track_id))
+        .collect();
+    if matching.is_empty() {
+        return Err(format!("No track group contains track {}", track_id).into())
+    }
+
+    for group in matching.iter() {
+        info!(" * {} (contains track)", group.name);
+    }
+    Ok(matching)
+}
+
+/// How many of an album's tracks a track group can be missing while still counting as the full album
+/// for `--full-albums-only`. This allows for the odd track being unavailable or removed by hand.
+fn allowed_missing_tracks(album_track_count: u32) -> u32 {
+    album_track_count / 10
+}
+
+/// Drop album track groups which contain only part of their album, if `--full-albums-only` is set.
+/// Each album's track count is only looked up once, no matter how many groups it appears in.
+fn filter_partial_albums(client: &Client,
+                         groups: Vec<TrackGroup>,
+                         opts: &cmd::GroupOpts)
+                         -> Result<Vec<TrackGroup>> {
+    if !opts.full_albums_only {
+        return Ok(groups)
+    }
+
+    let album_ids: Vec<String> = groups.iter().filter_map(|g| g.album_id.clone()).collect();
+    let albums = client.albums(album_ids.as_slice())?;
+
+    let mut out = Vec::with_capacity(groups.len());
+    for group in groups {
+        let album_track_count = group.album_id.as_ref().and_then(|id| albums.get(id)).map(|a| a.tracks.total);
+        if let Some(total) = album_track_count {
+            let present = group.tracks.iter().map(|t| t.id.as_str()).collect::<HashSet<_>>().len() as u32;
+            if present + allowed_missing_tracks(total) < total {
+                info!(" - {} (only {} of {} tracks)", group.name, present, total);
+                continue
+            }
+        }
+        out.push(group);
+    }
+
+    Ok(out)
+}
+
+/// Remove groups according to the genre filters in `opts`.
+fn filter_by_genre(client: &Client,
+                   groups: Vec<TrackGroup>,
+                   opts: &cmd::GroupOpts)
+                   -> Result<Vec<TrackGroup>> {
+    if opts.genres.is_empty() && opts.exclude_genres.is_empty() {
+        return Ok(groups)
+    }
+
+    let included: HashSet<String> = opts.genres.iter().map(|g| g.to_lowercase()).collect();
+    let excluded: HashSet<String> = opts.exclude_genres.iter().map(|g| g.to_lowercase()).collect();
+    let artist_genres = load_artist_genres(client, groups.as_slice())?;
+
+    let mut out = Vec::with_capacity(groups.len());
+    let mut not_included = 0usize;
+    for group in groups {
+        let genres: Vec<&String> = group.artists
+            .iter()
+            .filter_map(|a| a.id.as_ref())
+            .filter_map(|id| artist_genres.get(id))
+            .flatten()
+            .collect();
+
+        if let Some(genre) = genres.iter().find(|g| excluded.contains(**g)) {
+            info!(" - {} (excluded genre \"{}\")", group.name, genre);
+        } else if !included.is_empty() && !genres.iter().any(|g| included.contains(*g)) {
+            not_included += 1;
+        } else {
+            out.push(group);
+        }
+    }
+    if not_included > 0 {
+        info!("Dropped {} track groups not tagged with a --genre", not_included);
+    }
+
+    Ok(out)
+}
+
+/// Keep only the groups by one of the `--artist` artists, if any were given.
+fn filter_by_artist(groups: Vec<TrackGroup>, opts: &cmd::GroupOpts) -> Vec<TrackGroup> {
+    if opts.artists.is_empty() {
+        return groups
+    }
+
+    let is_wanted = |artist: &SimplifiedArtist| {
+        opts.artists.iter().any(|wanted| {
+            artist.name.eq_ignore_ascii_case(wanted)
+                || cmd::parse_spotify_id(cmd::SpotifyKind::Artist, wanted)
+                    .is_ok_and(|id| artist.id.as_ref() == Some(&id))
+        })
+    };
+    let before = groups.len();
+    let out: Vec<TrackGroup> = groups.into_iter().filter(|g| g.artists.iter().any(is_wanted)).collect();
+    info!("Dropped {} track groups not by an --artist", before - out.len());
+    out
+}
+
+/// Keep only the first group of each album in `groups`, since the same album can be in several
+/// playlists. Groups which aren't an album are all kept. Returns how many groups were removed.
+fn dedupe_albums(groups: &mut Vec<TrackGroup>) -> usize {
+    let mut seen_album_ids = HashSet::new();
+    let original_len = groups.len();
+    groups.retain(|g| g.album_id.as_ref().is_none_or(|id| seen_album_ids.insert(id.clone())));
+    original_len - groups.len()
+}
+
+/// Create a playlist from `src`, shuffled with `rng`. Albums in the shuffle `history` are less
+/// likely to be picked.
+///
+/// Returns the selected groups in playlist order (before any `--interleave`) and how many groups were
+/// left out once the goal duration was reached, or an error if the groups are shorter than
+/// `--min-duration` (unless `--allow-short`).
+fn create_playlist<R>(mut src: Vec<TrackGroup>,
+                      goal_duration: Option<Duration>,
+                      rng: &mut R,
+                      history: &HashMap<String, Vec<u64>>,
+                      opts: &cmd::ShuffleLibraryOpts)
+                      -> Result<(Vec<TrackGroup>, usize)>
+    where R: Rng {
+    let goal_duration = goal_duration.unwrap_or(Duration::from_secs(60u64 * 1200));
+
+    if !opts.allow_duplicates {
+        let removed = dedupe_albums(&mut src);
+        info!("Removed {} duplicate albums", removed);
+    }
+
+    if opts.shuffle_mode == cmd::ShuffleMode::Track {
+        // Every track becomes its own group, so the rest works the same on individual tracks
+        src = src
+            .into_iter()
+            .flat_map(|group| {
+                let TrackGroup { artists, tracks, .. } = group;
+                tracks.into_iter().map(move |track| TrackGroup {
+                    name: track.name.clone(),
+                    album_id: None,
+                    artists: artists.clone(),
+                    duration: track.duration,
+                    tracks: vec![track],
+                })
+            })
+            .collect();
+    }
+
+    // Sorting is stable, so groups which tie keep their library order
+    let sort_key = |group: &TrackGroup| group.name.to_lowercase();
+    match opts.order {
+        _ if opts.keep_playlist_order => {}
+        cmd::Order::Added => {}
+        cmd::Order::Album => src.sort_by_cached_key(sort_key),
+        cmd::Order::Artist => src.sort_by_cached_key(|group| {
+            (group.artists.first().map(|a| a.name.to_lowercase()), sort_key(group))
+        }),
+        cmd::Order::Duration => src.sort_by_key(|group| group.duration),
+        cmd::Order::Random if history.is_empty() => src.shuffle(rng),
+        cmd::Order::Random => {
+            // A weighted shuffle: each album picked `n` times recently has a weight of `1/(1+n)`,
+            // and sorting by `u^(1/weight)` for uniform `u` puts heavier groups first more often
+            let mut keyed: Vec<(f64, TrackGroup)> = src
+                .into_iter()
+                .map(|group| {
+                    let picks = group.album_id.as_ref().and_then(|id| history.get(id)).map_or(0, |p| p.len());
+                    (rng.gen::<f64>().powf((1 + picks) as f64), group)
+                })
+                .collect();
+            keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+            src = keyed.into_iter().map(|(_, group)| group).collect();
+        }
+    }
+
+    let mut playlist_duration = Duration::new(0, 0);
+    let mut out = Vec::new();
+    // Tracks in the output so far by primary artist, for `--artist-limit`
+    let mut artist_track_counts: HashMap<String, usize> = HashMap::new();
+    let mut capped_artists: Vec<String> = Vec::new();
+    let mut track_count = 0usize;
+    let candidate_count = src.len();
+    let mut cut_off = 0usize;
+    for (idx, group) in src.into_iter().enumerate() {
+        if playlist_duration > goal_duration {
+            cut_off = candidate_count - idx;
+            break
+        }
+
+        let artist_key = group.artists.first().map(|a| a.id.clone().unwrap_or_else(|| a.name.clone()));
+        if let (Some(limit), Some(key)) = (opts.artist_limit, artist_key.as_ref()) {
+            // Groups are added whole, so one which would take the artist past the limit is skipped
+            if artist_track_counts.get(key).cloned().unwrap_or(0) + group.tracks.len() > limit {
+                let name = &group.artists[0].name;
+                if !capped_artists.contains(name) {
+                    capped_artists.push(name.clone());
+                }
+                continue
+            }
+        }
+
+        if let Some(max) = opts.max_tracks {
+            if track_count >= max || (opts.no_partial_albums && track_count + group.tracks.len() > max) {
+                break
+            }
+        }
+
+        if let (Some(_), Some(key)) = (opts.artist_limit, artist_key) {
+            *artist_track_counts.entry(key).or_insert(0) += group.tracks.len();
+        }
+        info!(" + {}", group.name);
+        playlist_duration += group.duration;
+        track_count += group.tracks.len();
+        out.push(group);
+    }
+    if !capped_artists.is_empty() {
+        info!("Reached the artist limit for: {}", capped_artists.join(", "));
+    }
+
+    if !opts.allow_duplicates {
+        // The same track can appear on multiple albums (singles, compilations), so keep only the
+        // first occurrence of each
+        let mut seen_ids = HashSet::new();
+        let mut removed = 0usize;
+        for group in out.iter_mut() {
+            let original_len = group.tracks.len();
+            group.tracks.retain(|t| seen_ids.insert(t.id.clone()));
+            removed += original_len - group.tracks.len();
+            group.duration = group.tracks.iter().map(|t| t.duration).sum();
+        }
+        out.retain(|g| !g.tracks.is_empty());
+        playlist_duration = out.iter().map(|g| g.duration).sum();
+        info!("Removed {} duplicate tracks", removed);
+    }
+
+    if let Some(min) = opts.min_duration.filter(|min| playlist_duration < *min) {
+        let msg = format!("The shuffle is only {} minutes long, less than --min-duration of {} minutes",
+                          playlist_duration.as_secs() / 60,
+                          min.as_secs() / 60);
+        if !opts.allow_short {
+            return Err(format!("{} (are the filters too strict?)", msg).into())
+        }
+        warn!("{}", msg);
+    }
+
+    info!("Play time: {} hours ({} tracks)",
+          playlist_duration.as_secs_f64() / 3600.0,
+          out.iter().map(|g| g.tracks.len()).sum::<usize>());
+    Ok((out, cut_off))
+}
+
+/// The `--summary` of the track groups picked for the shuffle.
+struct ShuffleSummary<'a> {
+    pub tracks: usize,
+    pub groups: usize,
+    pub artists: usize,
+    pub duration: Duration,
+    pub longest: Option<&'a TrackGroup>,
+    pub shortest: Option<&'a TrackGroup>,
+    /// The number of groups left out because the shuffle reached the goal duration.
+    pub cut_off: usize,
+}
+
+impl<'a> ShuffleSummary<'a> {
+    pub fn new(groups: &'a [TrackGroup], cut_off: usize) -> ShuffleSummary<'a> {
+        let artists: HashSet<&String> = groups
+            .iter()
+            .flat_map(|g| g.tracks.iter().flat_map(|t| t.artists.iter()))
+            .collect();
+        ShuffleSummary {
+            tracks: groups.iter().map(|g| g.tracks.len()).sum(),
+            groups: groups.len(),
+            artists: artists.len(),
+            duration: groups.iter().map(|g| g.duration).sum(),
+            longest: groups.iter().max_by_key(|g| g.duration),
+            shortest: groups.iter().min_by_key(|g| g.duration),
+            cut_off,
+        }
+    }
+
+    /// Describe the summary for `--summary-json`.
+    pub fn to_json(&self) -> serde_json::Value {
+        let group_json = |group: Option<&TrackGroup>| group.map(|g| serde_json::json!({
+            "name": g.name,
+            "duration_ms": g.duration.as_millis() as u64,
+        }));
+        serde_json::json!({
+            "tracks": self.tracks,
+            "groups": self.groups,
+            "artists": self.artists,
+            "duration_ms": self.duration.as_millis() as u64,
+            "longest_group": group_json(self.longest),
+            "shortest_group": group_json(self.shortest),
+            "cut_off": self.cut_off,
+        })
+    }
+
+    /// Print the summary to stderr.
+    pub fn print(&self) {
+        let describe = |group: Option<&TrackGroup>| group.map_or("none".to_owned(), |g| {
+            format!("{} ({} minutes)", g.name, g.duration.as_secs() / 60)
+        });
+        eprintln!("Summary:");
+        eprintln!("  Tracks:         {}", self.tracks);
+        eprintln!("  Track groups:   {}", self.groups);
+        eprintln!("  Artists:        {}", self.artists);
+        eprintln!("  Play time:      {:.1} hours", self.duration.as_secs_f64() / 3600.0);
+        eprintln!("  Longest group:  {}", describe(self.longest));
+        eprintln!("  Shortest group: {}", describe(self.shortest));
+        eprintln!("  Left out by the target duration: {} track groups", self.cut_off);
+    }
+}
+
+/// Round-robin the tracks of `groups` for `--interleave`: the first track of each group in order,
+/// then the second of each, and so on, with groups dropping out once they run out of tracks. Each
+/// track becomes its own group so the order is kept.
+fn interleave_groups(groups: Vec<TrackGroup>) -> Vec<TrackGroup> {
+    let mut rotation: Vec<(TrackGroup, std::vec::IntoIter<Track>)> = groups
+        .into_iter()
+        .map(|mut g| {
+            let tracks = mem::take(&mut g.tracks);
+            (g, tracks.into_iter())
+        })
+        .collect();
+
+    let mut out = Vec::new();
+    while !rotation.is_empty() {
+        rotation.retain_mut(|(group, tracks)| match tracks.next() {
+            Some(track) => {
+                out.push(TrackGroup {
+                    name: track.name.clone(),
+                    album_id: None,
+                    artists: group.artists.clone(),
+                    duration: track.duration,
+                    tracks: vec![track],
+                });
+                true
+            }
+            None => false,
+        });
+    }
+    out
+}
+
+/// Get the random number generator to shuffle with: seeded with `seed` for a reproducible shuffle, or
+/// from the OS otherwise.
+fn shuffle_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+/// Get the track IDs of `groups` in order.
+fn group_track_ids(groups: &[TrackGroup]) -> Vec<String> {
+    groups.iter().flat_map(|g| g.tracks.iter().map(|t| t.id.clone())).collect()
+}
+
+/// Get the name of the playlist created by `--rotate` on `date`.
+fn rotation_name(template: &str, date: NaiveDate) -> String {
+    template.replace("{date}", date.format("%Y-%m-%d").to_string().as_str())
+}
+
+/// Get the date of a playlist created by `--rotate` from its `name`, or `None` if the name does not
+/// match the `template`.
+fn rotation_date(template: &str, name: &str) -> Option<NaiveDate> {
+    let (prefix, suffix) = template.split_once("{date}")?;
+
+    if name.len() < prefix.len() + suffix.len() || !name.starts_with(prefix) || !name.ends_with(suffix) {
+        return None
+    }
+    NaiveDate::parse_from_str(&name[prefix.len()..name.len() - suffix.len()], "%Y-%m-%d").ok()
+}
+
+/// Delete all but the newest `keep` playlists created by `--rotate` with `template`. Only playlists
+/// owned by the user whose names match the template are touched.
+fn prune_rotated_playlists(client: &Client, template: &str, keep: usize) -> Result<()> {
+    let mut rotated: Vec<(NaiveDate, SimplifiedPlaylist)> = client
+        .current_user_playlists()?
+        .into_iter()
+        .filter(|p| p.owner.id == client.user_id)
+        .filter_map(|p| rotation_date(template, p.name.as_str()).map(|date| (date, p)))
+        .collect();
+    rotated.sort_by_key(|(date, _)| std::cmp::Reverse(*date));
+
+    for (_, playlist) in rotated.iter().skip(keep) {
+        info!(" - Deleting rotated playlist {}", playlist.name);
+        client.delete_playlist(playlist.id.as_str())?;
+    }
+    Ok(())
+}
+
+/// Get the playlist ID for the shuffle output. This will either create a new playlist named `name`
+/// or pick the playlist with that name. The `--public` and `--collaborative` settings are applied to
+/// the playlist either way.
+fn get_or_create_shuffle_playlist_id(client: &Client,
+                                     name: &str,
+                                     opts: &cmd::ShuffleLibraryOpts)
+                                     -> Result<String> {
+    // Only touch the visibility of reused playlists if it was asked for
+    let collaborative = if opts.collaborative { Some(true) } else { None };
+    let public = if opts.public || opts.collaborative { Some(opts.public) } else { None };
+
+    let playlists = client.current_user_playlists()?;
+    let mut existing = playlists.iter().find(|p| p.name.as_str() == name);
+
+    // Rotated and split playlists are expected to be new and have names close to the previous ones
+    if existing.is_none() && opts.rotate.is_none() && opts.split_size.is_none() {
+        let suggestions = fuzzy::closest_matches(name, playlists.iter().map(|p| p.name.as_str()));
+        if let Some(best) = suggestions.first() {
+            let use_best = io::stdin().is_terminal()
+                && Confirm::new()
+                    .with_prompt(format!("No playlist named \"{}\" -- use \"{}\" instead?", name, best))
+                    .default(false)
+                    .interact()?;
+
+            if use_best {
+                existing = playlists.iter().find(|p| p.name.as_str() == *best);
+            } else {
+                info!("Creating playlist \"{}\" (similar existing playlists: \"{}\")",
+                      name,
+                      suggestions.join("\", \""));
+            }
+        }
+    }
+
+    if let Some(playlist) = existing {
+        debug!("Reusing existing playlist with ID={}", playlist.id);
+        if public.is_some() || opts.description.is_some() {
+            client.set_playlist_details(playlist.id.as_str(),
+                                        public,
+                                        collaborative,
+                                        opts.description.as_deref())?;
+        }
+        return Ok(playlist.id.clone())
+    }
+
+    let playlist_id = client.create_playlist(name, opts.description.as_deref(), opts.public)?;
+    if opts.collaborative {
+        // Playlists can't be created as collaborative, so this needs to happen after the fact
+        client.set_playlist_details(playlist_id.as_str(), None, collaborative, None)?;
+    }
+    Ok(playlist_id)
+}
+
+/// Load the track groups from `playlists` (and saved albums, if requested) and apply every filter.
+fn load_candidate_groups(client: &Client,
+                         playlists: &[SimplifiedPlaylist],
+                         resume_log: &mut ResumeLog,
+                         opts: &cmd::GroupOpts)
+                         -> Result<Vec<TrackGroup>> {
+    let aliases = match opts.album_aliases.as_ref() {
+        Some(path) => AlbumAliases::load(path)?,
+        None => AlbumAliases::default(),
+    };
+    let groups = load_groups(client, playlists, &aliases, resume_log, opts)?;
+    let groups = filter_explicit(groups, opts);
+    let groups = filter_partial_albums(client, groups, opts)?;
+    let groups = filter_by_artist(groups, opts);
+    let groups = filter_by_genre(client, groups, opts)?;
+    filter_by_track(groups, opts)
+}
+
+fn shuffle_library(settings: &Settings, opts: &cmd::ShuffleLibraryOpts) -> Result<()> {
+    if opts.dedupe_tracks {
+        warn!("--dedupe-tracks is the default now and can be left out");
+    }
+    if opts.groups.no_single_group {
+        warn!("--no-single-group is the default now and can be left out");
+    }
+    if opts.rotate == Some(0) {
+        return Err("--rotate must keep at least 1 playlist".into())
+    }
+    if opts.target_duration == Some(Duration::from_secs(0)) {
+        return Err("--target-duration must be longer than 0 seconds".into())
+    }
+    if opts.max_tracks == Some(0) {
+        return Err("--max-tracks must allow at least 1 track".into())
+    }
+    if opts.artist_limit == Some(0) {
+        return Err("--artist-limit must allow at least 1 track".into())
+    }
+    if opts.public && opts.collaborative {
+        return Err("Spotify does not allow collaborative playlists to be public".into())
+    }
+    if opts.device.is_some() && !opts.start_playing {
+        return Err("--device only applies with --start-playing".into())
+    }
+    if opts.groups.playlist_as_album_min >= opts.groups.playlist_as_album_max {
+        return Err("--playlist-as-album-min must be shorter than --playlist-as-album-max".into())
+    }
+    if let Some(path) = opts.export.as_ref() {
+        ExportFormat::from_path(path)?;
+    }
+    if opts.split_size == Some(0) {
+        return Err("--split-size must allow at least 1 track".into())
+    }
+    if opts.every == Some(Duration::from_secs(0)) {
+        return Err("--every must be longer than 0 seconds".into())
+    }
+    if opts.every.is_some() && opts.interactive {
+        return Err("--every can't be combined with --interactive".into())
+    }
+
+    let interval = match opts.every {
+        Some(interval) => interval,
+        None => return shuffle_once(settings, opts),
+    };
+
+    // Finish the current shuffle on SIGINT or SIGTERM instead of leaving the playlist half written,
+    // unless it is sent twice
+    let (stop_tx, stop_rx) = mpsc::channel();
+    let stopping = AtomicBool::new(false);
+    ctrlc::set_handler(move || {
+        if stopping.swap(true, Ordering::SeqCst) {
+            process::exit(130)
+        }
+        let _ = stop_tx.send(());
+    })?;
+
+    let timestamp = || Local::now().format("%Y-%m-%d %H:%M:%S");
+    loop {
+        info!("[{}] Regenerating shuffle", timestamp());
+        // The client is created again for each run, which reloads (and refreshes) the cached token
+        match shuffle_once(settings, opts) {
+            Ok(()) => info!("[{}] Done", timestamp()),
+            Err(e) => error!("[{}] Shuffle failed: {}", timestamp(), e),
+        }
+
+        match stop_rx.recv_timeout(interval) {
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            _ => {
+                info!("[{}] Stopping", timestamp());
+                return Ok(())
+            }
+        }
+    }
+}
+
+/// The snapshot IDs of `playlists`, to tell whether any of them changed.
+fn library_snapshot(playlists: &[SimplifiedPlaylist]) -> Vec<String> {
+    let mut snapshot: Vec<String> = playlists.iter().map(|p| format!("{}:{}", p.id, p.snapshot_id)).collect();
+    snapshot.sort();
+    snapshot
+}
+
+/// Load the library snapshots recorded for `--if-changed`, keyed by output playlist.
+fn load_library_snapshots(config_dir: &Path) -> HashMap<String, Vec<String>> {
+    File::open(config_dir.join(LIBRARY_SNAPSHOTS_FILE))
+        .ok()
+        .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+        .unwrap_or_default()
+}
+
+/// Record the library snapshot a shuffle was generated from. Like `save_last_shuffle`, failing to do
+/// this is not fatal.
+fn save_library_snapshot(config_dir: &Path, output: &str, snapshot: Vec<String>) {
+    let mut snapshots = load_library_snapshots(config_dir);
+    snapshots.insert(output.to_owned(), snapshot);
+
+    let path = config_dir.join(LIBRARY_SNAPSHOTS_FILE);
+    let res = File::create(&path)
+        .map_err(SpotctlError::from)
+        .and_then(|file| Ok(serde_json::to_writer(BufWriter::new(file), &snapshots)?));
+    if let Err(e) = res {
+        warn!("Could not record the library snapshot in {}: {}", path.display(), e);
+    }
+}
+
+/// Generate and write a single shuffle.
+fn shuffle_once(settings: &Settings, opts: &cmd::ShuffleLibraryOpts) -> Result<()> {
+    let mut required_scopes = vec!["playlist-read-private",
+                                   "playlist-modify-private",
+                                   "playlist-modify-public"];
+    if opts.groups.include_saved_albums || opts.groups.include_liked {
+        required_scopes.push("user-library-read");
+    }
+    if opts.start_playing {
+        required_scopes.extend(&["user-read-playback-state", "user-modify-playback-state"]);
+    }
+    if opts.cover.is_some() {
+        required_scopes.push("ugc-image-upload");
+    }
+    // Check the cover before doing any work, so a bad image doesn't fail the run at the very end
+    let cover = opts.cover.as_deref().map(load_cover).transpose()?;
+    let client = Client::new(settings, required_scopes.as_slice())?;
+
+    let source = &opts.groups.source;
+    let mut playlists = source_playlists(&client, source, |p| shuffle_exclusion_reason(p, opts))?;
+    let mut goal_duration = opts.target_duration;
+    if opts.interactive {
+        if io::stdin().is_terminal() {
+            let (selected, duration) = select_interactively(playlists)?;
+            playlists = selected;
+            goal_duration = duration.or(goal_duration);
+        } else {
+            warn!("stdin is not a terminal -- ignoring --interactive");
+        }
+    }
+
+    // Rotated playlists get a new name every day, so they are tracked by their template
+    let output_key = if opts.rotate.is_some() { opts.name_template.as_str() } else { source.output.as_str() };
+    let snapshot = library_snapshot(playlists.as_slice());
+    if opts.if_changed && load_library_snapshots(&settings.config_dir).get(output_key) == Some(&snapshot) {
+        info!("No source playlist changed since the last shuffle -- skipping");
+        return Ok(())
+    }
+
+    let mut resume_log = ResumeLog::open(&settings.config_dir, RESUME_FILE, opts.groups.resume);
+    let groups = load_candidate_groups(&client, playlists.as_slice(), &mut resume_log, &opts.groups)?;
+
+    let mut history = if opts.forget_history {
+        HashMap::new()
+    } else {
+        load_shuffle_history(&settings.config_dir)
+    };
+    // The history changes with every run, so a seeded shuffle can only be reproduced without it
+    let no_history = HashMap::new();
+    let weights = if opts.seed.is_some() { &no_history } else { &history };
+    let (groups, cut_off) =
+        create_playlist(groups, goal_duration, &mut shuffle_rng(opts.seed), weights, opts)?;
+    // With `--json` the summary is part of the result, and with `--dry-run` stdout is taken by the
+    // track IDs, so only print the JSON summary to stdout when it would be on its own there
+    let summary = ShuffleSummary::new(groups.as_slice(), cut_off);
+    if opts.summary {
+        summary.print();
+    }
+    let summary_json = if opts.summary_json { Some(summary.to_json()) } else { None };
+    match summary_json.as_ref() {
+        Some(summary) if !settings.json && opts.dry_run => eprintln!("{}", summary),
+        Some(summary) if !settings.json => println!("{}", summary),
+        _ => {}
+    }
+    // Interleaving mixes the groups' tracks together, so their albums have to be taken first
+    let picked_album_ids: Vec<String> = groups.iter().filter_map(|g| g.album_id.clone()).collect();
+    let groups = if opts.interleave { interleave_groups(groups) } else { groups };
+    let groups_json: Vec<serde_json::Value> = groups
+        .iter()
+        .map(|g| serde_json::json!({
+            "name": g.name,
+            "album_id": g.album_id,
+            "tracks": g.tracks.len(),
+            "duration_ms": g.duration.as_millis() as u64,
+        }))
+        .collect();
+    let total_duration: Duration = groups.iter().map(|g| g.duration).sum();
+
+    let playlist_name = match opts.rotate {
+        Some(_) => rotation_name(opts.name_template.as_str(), Local::now().date_naive()),
+        None => source.output.clone(),
+    };
+    if let Some(path) = opts.export.as_ref() {
+        export_shuffle(path, groups.as_slice())?;
+    }
+    if opts.dry_run {
+        // Track IDs go to stdout on their own so they can be piped elsewhere
+        if settings.json {
+            println!("{}", serde_json::json!({
+                "playlist_name": playlist_name,
+                "duration_ms": total_duration.as_millis() as u64,
+                "groups": groups_json,
+                "track_ids": group_track_ids(groups.as_slice()),
+                "summary": summary_json,
+            }));
+        } else {
+            for group in groups.iter() {
+                info!(" + {} ({} tracks)", group.name, group.tracks.len());
+                for track in group.tracks.iter() {
+                    println!("{}", track.id);
+                }
+            }
+        }
+        info!("Dry run -- {} was not changed", playlist_name);
+        resume_log.finish();
+        return Ok(())
+    }
+    let parts = match opts.split_size {
+        Some(size) => split_shuffle(groups, size)
+            .into_iter()
+            .enumerate()
+            .map(|(idx, part)| (format!("{} {}", playlist_name, idx + 1), part))
+            .collect(),
+        None => vec![(playlist_name.clone(), groups)],
+    };
+    let part_count = parts.len();
+
+    let show_changes = !settings.quiet || settings.json;
+    let mut written = Vec::with_capacity(part_count);
+    for (name, part) in parts {
+        let playlist_id = get_or_create_shuffle_playlist_id(&client, name.as_str(), opts)?;
+
+        let replaces = !opts.yes && !opts.append && !opts.prepend;
+        let before = if show_changes || replaces {
+            client.playlist_tracks(playlist_id.as_str())?
+        } else {
+            Vec::new()
+        };
+        if replaces {
+            let incoming = part.iter().map(|g| g.tracks.len()).sum();
+            confirm_replace(&client, playlist_id.as_str(), name.as_str(), before.as_slice(), incoming, opts)?;
+        }
+        write_shuffle(&client, playlist_id.as_str(), part, opts)?;
+        if let Some(cover) = cover.as_ref() {
+            client.set_playlist_cover(playlist_id.as_str(), cover.as_slice())?;
+            info!("Uploaded cover image");
+        }
+        let changes = if show_changes {
+            let after = client.playlist_tracks(playlist_id.as_str())?;
+            Some(AlbumChanges::between(before.as_slice(), after.as_slice()))
+        } else {
+            None
+        };
+        if let (Some(changes), false) = (changes.as_ref(), settings.quiet) {
+            changes.print(name.as_str());
+        }
+        written.push((playlist_id, name, changes));
+    }
+    if opts.split_size.is_some() {
+        clear_stale_split_playlists(&client, playlist_name.as_str(), part_count, opts)?;
+    }
+
+    if let Some(keep) = opts.rotate {
+        prune_rotated_playlists(&client, opts.name_template.as_str(), keep)?;
+    }
+
+    // With `--split-size`, playback starts from the first part
+    let (playlist_id, playlist_name, _) = &written[0];
+    if opts.start_playing {
+        let device = resolve_device(&client, opts.device.as_deref())?;
+        client.play_playlist(playlist_id.as_str(), device.as_ref().map(|d| d.id.clone()))?;
+        match device {
+            Some(device) => info!("Started playback on {}", device.name),
+            None => info!("Started playback on the active device"),
+        }
+    }
+
+    resume_log.finish();
+    save_library_snapshot(&settings.config_dir, output_key, snapshot);
+    let now = unix_time();
+    for album_id in picked_album_ids {
+        history.entry(album_id).or_default().push(now);
+    }
+    save_shuffle_history(&settings.config_dir, history);
+    if settings.json {
+        let playlists: Vec<serde_json::Value> = written
+            .iter()
+            .map(|(id, name, changes)| serde_json::json!({
+                "playlist_id": id,
+                "playlist_name": name,
+                "changes": changes.as_ref().map(|c| c.to_json()),
+            }))
+            .collect();
+        println!("{}", serde_json::json!({
+            "playlist_id": playlist_id,
+            "playlist_name": playlist_name,
+            "duration_ms": total_duration.as_millis() as u64,
+            "groups": groups_json,
+            "changes": written[0].2.as_ref().map(|c| c.to_json()),
+            "playlists": playlists,
+            "summary": summary_json,
+        }));
+    }
+    Ok(())
+}
+
+/// Split the shuffle into parts of at most `size` tracks for `--split-size`, splitting track groups
+/// between parts where needed. There is always at least one part.
+fn split_shuffle(groups: Vec<TrackGroup>, size: usize) -> Vec<Vec<TrackGroup>> {
+    let mut parts = vec![Vec::new()];
+    let mut part_len = 0usize;
+    for mut group in groups {
+        while !group.tracks.is_empty() {
+            if part_len == size {
+                parts.push(Vec::new());
+                part_len = 0;
+            }
+            let rest = group.tracks.split_off(group.tracks.len().min(size - part_len));
+            let tracks = mem::replace(&mut group.tracks, rest);
+            part_len += tracks.len();
+            parts.last_mut().unwrap().push(TrackGroup {
+                name: group.name.clone(),
+                album_id: group.album_id.clone(),
+                artists: group.artists.clone(),
+                duration: tracks.iter().map(|t| t.duration).sum(),
+                tracks,
+            });
+        }
+    }
+    parts
+}
+
+/// Check if `name` is one of the numbered playlists `--split-size` writes for the output playlist
+/// named `output`.
+fn is_split_part(name: &str, output: &str) -> bool {
+    name.strip_prefix(output)
+        .and_then(|rest| rest.strip_prefix(' '))
+        .is_some_and(|part| part.parse::<usize>().is_ok())
+}
+
+/// Empty the numbered playlists left over from an earlier `--split-size` shuffle which needed more
+/// than `part_count` parts, so their old tracks don't linger. Only playlists owned by the user are
+/// touched, and like replacing a playlist, this asks first unless `--yes`.
+fn clear_stale_split_playlists(client: &Client,
+                               name: &str,
+                               part_count: usize,
+                               opts: &cmd::ShuffleLibraryOpts)
+                               -> Result<()> {
+    let prefix = format!("{} ", name);
+    for playlist in client.current_user_playlists()? {
+        // Followed playlists of other users can have the same names, but were never written here
+        if playlist.owner.id != client.user_id || !is_split_part(playlist.name.as_str(), name) {
+            continue
+        }
+        let part: usize = playlist.name[prefix.len()..].parse()?;
+        if part > part_count {
+            if !opts.yes {
+                let current = client.playlist_tracks(playlist.id.as_str())?;
+                if current.is_empty() {
+                    continue
+                }
+                confirm_replace(client,
+                                playlist.id.as_str(),
+                                playlist.name.as_str(),
+                                current.as_slice(),
+                                0,
+                                opts)?;
+            }
+            client.set_playlist(playlist.id.as_str(), &[])?;
+            info!(" - {} (cleared)", playlist.name);
+        }
+    }
+    Ok(())
+}
+
+/// The albums added to and removed from a playlist by a run of `shuffle-library`.
+struct AlbumChanges {
+    /// The ID and name of each album which is only in the new playlist.
+    pub added: Vec<(String, String)>,
+    /// The ID and name of each album which is only in the old playlist.
+    pub removed: Vec<(String, String)>,
+    pub unchanged: usize,
+}
+
+impl AlbumChanges {
+    pub fn between(before: &[PlaylistTrack], after: &[PlaylistTrack]) -> AlbumChanges {
+        fn albums(tracks: &[PlaylistTrack]) -> Vec<(String, String)> {
+            let mut seen = HashSet::new();
+            tracks
+                .iter()
+                .filter_map(|t| t.track.album.id.clone().map(|id| (id, t.track.album.name.clone())))
+                .filter(|(id, _)| seen.insert(id.clone()))
+                .collect()
+        }
+
+        let (before, after) = (albums(before), albums(after));
+        let before_ids: HashSet<&String> = before.iter().map(|(id, _)| id).collect();
+        let after_ids: HashSet<&String> = after.iter().map(|(id, _)| id).collect();
+
+        AlbumChanges {
+            unchanged: after.iter().filter(|(id, _)| before_ids.contains(id)).count(),
+            added: after.iter().filter(|(id, _)| !before_ids.contains(id)).cloned().collect(),
+            removed: before.iter().filter(|(id, _)| !after_ids.contains(id)).cloned().collect(),
+        }
+    }
+
+    pub fn print(&self, playlist_name: &str) {
+        eprintln!("Changes to {}:", playlist_name);
+        for (_, name) in self.added.iter() {
+            eprintln!(" + {}", name);
+        }
+        for (_, name) in self.removed.iter() {
+            eprintln!(" - {}", name);
+        }
+        eprintln!("{} albums added, {} removed, {} unchanged",
+                  self.added.len(),
+                  self.removed.len(),
+                  self.unchanged);
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let albums = |albums: &[(String, String)]| -> Vec<serde_json::Value> {
+            albums.iter().map(|(id, name)| serde_json::json!({ "id": id, "name": name })).collect()
+        };
+        serde_json::json!({
+            "added": albums(self.added.as_slice()),
+            "removed": albums(self.removed.as_slice()),
+            "unchanged": self.unchanged,
+        })
+    }
+}
+
+/// Queue the tracks of `count` random albums from the library onto the current playback, in album
+/// order.
+fn queue_next_albums(settings: &Settings,
+                     count: usize,
+                     seed: Option<u64>,
+                     device: Option<&str>,
+                     opts: &cmd::GroupOpts)
+                     -> Result<()> {
+    let mut required_scopes = vec!["playlist-read-private",
+                                   "user-read-playback-state",
+                                   "user-modify-playback-state"];
+    if opts.include_saved_albums || opts.include_liked {
+        required_scopes.push("user-library-read");
+    }
+    let client = Client::new(settings, required_scopes.as_slice())?;
+
+    let playlists = source_playlists(&client, &opts.source, |p| exclusion_reason(p, &opts.source))?;
+    let mut resume_log = ResumeLog::open(&settings.config_dir, QUEUE_RESUME_FILE, opts.resume);
+    let mut albums = load_candidate_groups(&client, playlists.as_slice(), &mut resume_log, opts)?;
+    albums.retain(|g| g.album_id.is_some());
+    // Album IDs already have `--album-aliases` applied, so this also catches other editions
+    dedupe_albums(&mut albums);
+    albums.shuffle(&mut shuffle_rng(seed));
+    albums.truncate(count);
+    if albums.is_empty() {
+        return Err("No albums to queue".into())
+    }
+
+    // Look for the device first, since there is no point queueing anything without one
+    let device = resolve_device(&client, device)?;
+    let device_id = device.as_ref().map(|d| d.id.as_str());
+    for album in albums.iter() {
+        for track in album.tracks.iter() {
+            client.queue_track(track.id.as_str(), device_id)?;
+        }
+        info!(" + {} ({} tracks)", album.name, album.tracks.len());
+    }
+    info!("Queued {} albums on {}",
+          albums.len(),
+          device.as_ref().map_or("the active device", |d| d.name.as_str()));
+
+    resume_log.finish();
+    if settings.json {
+        let albums: Vec<serde_json::Value> = albums
+            .iter()
+            .map(|album| serde_json::json!({
+                "id": album.album_id,
+                "name": album.name,
+                "tracks": album.tracks.len(),
+            }))
+            .collect();
+        println!("{}", serde_json::json!({
+            "device": device.as_ref().map(|d| d.name.as_str()),
+            "albums": albums,
+        }));
+    }
+    Ok(())
+}
+
+/// The largest cover image Spotify accepts, after base64 encoding.
+const MAX_COVER_SIZE: usize = 256 * 1024;
+
+/// The file formats `--export` can write, chosen by the file's extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ExportFormat {
+    /// An extended M3U playlist of `spotify:track:` URIs.
+    M3u,
+    /// A JSON list of tracks.
+    Json,
+}
+
+impl ExportFormat {
+    pub fn from_path(path: &Path) -> Result<ExportFormat> {
+        match path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase()).as_deref() {
+            Some("m3u") | Some("m3u8") => Ok(ExportFormat::M3u),
+            Some("json") => Ok(ExportFormat::Json),
+            _ => Err(format!("Can't tell what to export to {} (expected a .m3u, .m3u8 or .json file)",
+                             path.display()).into()),
+        }
+    }
+}
+
+/// Write the tracks of `groups` to `path` for `--export`, in playlist order.
+fn export_shuffle(path: &Path, groups: &[TrackGroup]) -> Result<()> {
+    let tracks = groups.iter().flat_map(|g| g.tracks.iter().map(move |t| (g, t)));
+    let contents = match ExportFormat::from_path(path)? {
+        ExportFormat::M3u => {
+            let mut out = String::from("#EXTM3U\n");
+            for (_, track) in tracks {
+                out.push_str(format!("#EXTINF:{},{} - {}\nspotify:track:{}\n",
+                                     track.duration.as_secs(),
+                                     track.artists.join(", "),
+                                     track.name,
+                                     track.id).as_str());
+            }
+            out
+        }
+        ExportFormat::Json => {
+            let tracks: Vec<serde_json::Value> = tracks
+                .map(|(group, track)| serde_json::json!({
+                    "id": track.id,
+                    "name": track.name,
+                    "artists": track.artists,
+                    "duration_ms": track.duration.as_millis() as u64,
+                    "group": group.name,
+                }))
+                .collect();
+            serde_json::to_string_pretty(&tracks)?
+        }
+    };
+    fs::write(path, contents).map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+    info!("Exported the shuffle to {}", path.display());
+    Ok(())
+}
+
+/// Read the track references from a file written by `--export`, `--dry-run --json` or `backup-all`,
+/// or any M3U playlist of Spotify tracks. They are returned as written, without checking them.
+fn read_exported_tracks(path: &Path) -> Result<Vec<String>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    match ExportFormat::from_path(path)? {
+        ExportFormat::M3u => Ok(contents
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.to_owned())
+            .collect()),
+        ExportFormat::Json => {
+            let parse_error =
+                || SpotctlError::Parse(format!("{} does not contain a list of tracks", path.display()));
+            let value: serde_json::Value = serde_json::from_str(contents.as_str())
+                .map_err(|e| SpotctlError::Parse(format!("Could not parse {}: {}", path.display(), e)))?;
+            let items = match value.get("track_ids").unwrap_or(&value) {
+                serde_json::Value::Array(items) => items,
+                _ => return Err(parse_error()),
+            };
+            // Exported tracks are objects, while backups and dry runs list bare IDs
+            Ok(items
+                .iter()
+                .map(|item| item.get("id").unwrap_or(item).as_str().unwrap_or_default().to_owned())
+                .collect())
+        }
+    }
+}
+
+/// Replace the contents of the playlist named `name` with the tracks in the file at `path`, such as a
+/// shuffle saved with `--export`. The playlist is created if it does not exist.
+fn restore_playlist(settings: &Settings, path: &Path, name: &str) -> Result<()> {
+    let tracks = read_exported_tracks(path)?;
+    let mut track_ids = Vec::with_capacity(tracks.len());
+    let mut skipped = 0usize;
+    for track in tracks.iter() {
+        match cmd::parse_track_id(track.as_str()) {
+            Ok(id) => track_ids.push(id),
+            Err(e) => {
+                info!(" ! Skipping {}", e);
+                skipped += 1;
+            }
+        }
+    }
+    if track_ids.is_empty() {
+        return Err(format!("{} contains no Spotify tracks", path.display()).into())
+    }
+
+    let required_scopes = ["playlist-read-private", "playlist-modify-private", "playlist-modify-public"];
+    let client = Client::new(settings, &required_scopes)?;
+    let playlists = client.current_user_playlists()?;
+    let playlist_id = match playlists.iter().find(|p| p.name == name) {
+        Some(playlist) => {
+            let track_count = playlist.tracks.get("total").and_then(|t| t.as_u64()).unwrap_or(0);
+            info!("Replacing the {} tracks of \"{}\"", track_count, name);
+            playlist.id.clone()
+        }
+        None => {
+            // A typo would otherwise quietly restore into a new playlist
+            let missing = format!("No playlist named \"{}\"", name);
+            let suggested = suggest_playlists(missing.clone(), name, playlists.as_slice());
+            if suggested != missing {
+                if !io::stdin().is_terminal() {
+                    return Err(suggested.into())
+                }
+                let confirmed = Confirm::new()
+                    .with_prompt(format!("{} Create it?", suggested))
+                    .default(false)
+                    .interact()?;
+                if !confirmed {
+                    return Err(format!("Cancelled -- \"{}\" was not created", name).into())
+                }
+            }
+            info!("Creating playlist \"{}\"", name);
+            client.create_playlist(name, None, false)?
+        }
+    };
+    client.set_playlist(playlist_id.as_str(), track_ids.as_slice())?;
+
+    info!("Restored {} tracks to \"{}\" ({} skipped)", track_ids.len(), name, skipped);
+    if settings.json {
+        println!("{}", serde_json::json!({
+            "playlist_id": playlist_id,
+            "added": track_ids.len(),
+            "skipped": skipped,
+        }));
+    }
+    Ok(())
+}
+
+/// Read a cover image for `--cover`, checking that Spotify will accept it.
+fn load_cover(path: &Path) -> Result<Vec<u8>> {
+    let jpeg = fs::read(path).map_err(|e| format!("Could not read cover image {}: {}", path.display(), e))?;
+    if !jpeg.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        return Err(format!("Cover image {} is not a JPEG", path.display()).into())
+    }
+
+    let encoded_size = jpeg.len().div_ceil(3) * 4;
+    if encoded_size > MAX_COVER_SIZE {
+        return Err(format!("Cover image {} is too large ({} KB encoded, but Spotify allows {} KB)",
+                           path.display(),
+                           encoded_size / 1024,
+                           MAX_COVER_SIZE / 1024).into())
+    }
+    Ok(jpeg)
+}
+
+/// Find the device to start playback on. With a `name` (a device name or ID), that device is used.
+/// Otherwise this returns `None` if a device is already active, meaning Spotify picks it, or asks
+/// which device to use.
+fn resolve_device(client: &Client, name: Option<&str>) -> Result<Option<Device>> {
+    let devices = client.devices()?;
+    if devices.is_empty() {
+        return Err("No Spotify devices are available -- open Spotify on the device to play on".into())
+    }
+    let device_names = || devices.iter().map(|d| d.name.as_str()).collect::<Vec<_>>().join("\", \"");
+
+    if let Some(name) = name {
+        return match devices.iter().find(|d| d.id == name || d.name.eq_ignore_ascii_case(name)) {
+            Some(device) => Ok(Some(device.clone())),
+            None => Err(format!("No device named \"{}\" (available: \"{}\")", name, device_names()).into()),
+        }
+    }
+
+    if devices.iter().any(|d| d.is_active) {
+        Ok(None)
+    } else if devices.len() == 1 {
+        Ok(Some(devices[0].clone()))
+    } else if io::stdin().is_terminal() {
+        let chosen = Select::new()
+            .with_prompt("No device is active -- which should play the shuffle?")
+            .items(&devices.iter().map(|d| d.name.as_str()).collect::<Vec<_>>())
+            .default(0)
+            .interact()?;
+        Ok(Some(devices[chosen].clone()))
+    } else {
+        Err(format!("No device is active -- pick one with --device (available: \"{}\")",
+                    device_names()).into())
+    }
+}
+
+/// Ask before replacing the `current` tracks of the playlist, unless it only holds what the previous
+/// shuffle wrote (so nothing is lost). Without a terminal to ask on, this refuses; `--yes` skips the
+/// check entirely.
+fn confirm_replace(client: &Client,
+                   playlist_id: &str,
+                   name: &str,
+                   current: &[PlaylistTrack],
+                   incoming: usize,
+                   opts: &cmd::ShuffleLibraryOpts)
+                   -> Result<()> {
+    if current.is_empty() {
+        return Ok(())
+    }
+    if let Some(previous) = load_last_shuffles(&client.config_dir).remove(playlist_id) {
+        // Tracks added by hand since are kept with `--preserve-shuffle-extras`
+        let previous: HashSet<String> = previous.into_iter().collect();
+        let only_shuffled = current
+            .iter()
+            .all(|t| t.track.id.as_ref().is_some_and(|id| previous.contains(id)));
+        if only_shuffled || opts.preserve_shuffle_extras {
+            return Ok(())
+        }
+    }
+
+    if !io::stdin().is_terminal() {
+        return Err(format!("Refusing to replace the {} tracks of \"{}\", which weren't all written by a \
+                            shuffle, without --yes",
+                           current.len(),
+                           name).into())
+    }
+    let confirmed = Confirm::new()
+        .with_prompt(format!("Replace the {} tracks of \"{}\" with {} shuffled tracks?",
+                             current.len(),
+                             name,
+                             incoming))
+        .default(false)
+        .interact()?;
+    if !confirmed {
+        return Err(format!("Cancelled -- \"{}\" was not changed", name).into())
+    }
+    Ok(())
+}
+
+/// Write the selected `groups` to the output playlist according to `opts`.
+fn write_shuffle(client: &Client,
+                 playlist_id: &str,
+                 groups: Vec<TrackGroup>,
+                 opts: &cmd::ShuffleLibraryOpts)
+                 -> Result<()> {
+    let previous_track_ids = load_last_shuffles(&client.config_dir).remove(playlist_id);
+
+    if opts.append || opts.prepend {
+        let (mut added, expected) = merge_into_playlist(client, playlist_id, groups, opts.prepend)?;
+        if opts.verify {
+            verify_playlist(client, playlist_id, expected.as_slice())?;
+        }
+        let mut recorded = previous_track_ids.unwrap_or_default();
+        recorded.append(&mut added);
+        save_last_shuffle(&client.config_dir, playlist_id, recorded);
+        return Ok(())
+    }
+
+    let track_ids = group_track_ids(groups.as_slice());
+    if opts.reorder {
+        // Local files have no ID and can't be matched, so their presence forces a replace
+        let current: Option<Vec<String>> = client
+            .playlist_tracks(playlist_id)?
+            .into_iter()
+            .map(|t| t.track.id)
+            .collect();
+
+        if let Some(current) = current {
+            if is_same_track_set(current.as_slice(), track_ids.as_slice()) {
+                let moves = client.reorder_playlist(playlist_id, current, track_ids.as_slice())?;
+                info!("Reordered playlist in place with {} moves", moves);
+                if opts.verify {
+                    verify_playlist(client, playlist_id, track_ids.as_slice())?;
+                }
+                save_last_shuffle(&client.config_dir, playlist_id, track_ids);
+                return Ok(())
+            }
+        }
+        info!("Tracks differ from the existing playlist -- replacing its contents");
+    }
+
+    let extras = if opts.preserve_shuffle_extras {
+        match previous_track_ids {
+            Some(previous) => find_shuffle_extras(client, playlist_id, previous, track_ids.as_slice())?,
+            None => {
+                info!("No record of the previous shuffle -- can't tell which tracks were added by hand");
+                Vec::new()
+            }
+        }
+    } else {
+        Vec::new()
+    };
+
+    client.set_playlist(playlist_id, track_ids.as_slice())?;
+    if !extras.is_empty() {
+        info!("Keeping {} tracks added by hand", extras.len());
+        client.add_tracks(playlist_id, extras.as_slice(), None)?;
+    }
+
+    if opts.verify {
+        let mut expected = track_ids.clone();
+        expected.extend(extras);
+        verify_playlist(client, playlist_id, expected.as_slice())?;
+    }
+
+    save_last_shuffle(&client.config_dir, playlist_id, track_ids);
+    Ok(())
+}
+
+/// Find the tracks in the playlist which were not part of the `previous` shuffle (and so were added
+/// by hand) and are not already part of the new shuffle.
+fn find_shuffle_extras(client: &Client,
+                       playlist_id: &str,
+                       previous: Vec<String>,
+                       track_ids: &[String])
+                       -> Result<Vec<String>> {
+    let mut known: HashSet<String> = HashSet::from_iter(previous);
+    known.extend(track_ids.iter().cloned());
+
+    Ok(client
+        .playlist_tracks(playlist_id)?
+        .into_iter()
+        .filter_map(|t| t.track.id)
+        .filter(|id| known.insert(id.clone()))
+        .collect())
+}
+
+/// Check that the playlist contains exactly the `expected` tracks after writing it. If the playlist is
+/// missing tracks from the end, as happens when adding a chunk of tracks silently fails, they are
+/// added again. Any other mismatch is only reported.
+fn verify_playlist(client: &Client, playlist_id: &str, expected: &[String]) -> Result<()> {
+    let fetch = || -> Result<Vec<String>> {
+        Ok(client.playlist_tracks(playlist_id)?.into_iter().filter_map(|t| t.track.id).collect())
+    };
+
+    let mut actual = fetch()?;
+    if actual.len() < expected.len() && expected.starts_with(actual.as_slice()) {
+        let missing = &expected[actual.len()..];
+        info!("Playlist is missing its last {} tracks -- adding them again", missing.len());
+        client.add_tracks(playlist_id, missing, None)?;
+        actual = fetch()?;
+    }
+
+    if actual == expected {
+        info!("Verified playlist contains all {} tracks", expected.len());
+    } else {
+        warn!("Playlist contains {} tracks, but {} were written",
+              actual.len(),
+              expected.len());
+    }
+    Ok(())
+}
+
+/// Load the times each album was picked for a shuffle within `SHUFFLE_HISTORY_WINDOW`, keyed by
+/// album ID.
+fn load_shuffle_history(config_dir: &Path) -> HashMap<String, Vec<u64>> {
+    let mut history: HashMap<String, Vec<u64>> = File::open(config_dir.join(SHUFFLE_HISTORY_FILE))
+        .ok()
+        .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+        .unwrap_or_default();
+
+    let cutoff = unix_time().saturating_sub(SHUFFLE_HISTORY_WINDOW.as_secs());
+    for picks in history.values_mut() {
+        picks.retain(|t| *t >= cutoff);
+    }
+    history.retain(|_, picks| !picks.is_empty());
+    history
+}
+
+fn save_shuffle_history(config_dir: &Path, history: HashMap<String, Vec<u64>>) {
+    let path = config_dir.join(SHUFFLE_HISTORY_FILE);
+    let res = File::create(&path)
+        .map_err(SpotctlError::from)
+        .and_then(|file| Ok(serde_json::to_writer(BufWriter::new(file), &history)?));
+    if let Err(e) = res {
+        warn!("Could not record the shuffle history in {}: {}", path.display(), e);
+    }
+}
+
+/// Load the track IDs of the last shuffle written to each output playlist, keyed by playlist ID.
+fn load_last_shuffles(config_dir: &Path) -> HashMap<String, Vec<String>> {
+    File::open(config_dir.join(LAST_SHUFFLE_FILE))
+        .ok()
+        .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+        .unwrap_or_default()
+}
+
+/// Record `track_ids` as the last shuffle written to `playlist_id`. Failing to do this is not fatal,
+/// since the playlist itself has already been written.
+fn save_last_shuffle(config_dir: &Path, playlist_id: &str, track_ids: Vec<String>) {
+    let mut last_shuffles = load_last_shuffles(config_dir);
+    last_shuffles.insert(playlist_id.to_owned(), track_ids);
+
+    let path = config_dir.join(LAST_SHUFFLE_FILE);
+    let res = File::create(&path)
+        .map_err(SpotctlError::from)
+        .and_then(|file| Ok(serde_json::to_writer(BufWriter::new(file), &last_shuffles)?));
+    if let Err(e) = res {
+        warn!("Could not record the last shuffle in {}: {}", path.display(), e);
+    }
+}
+
+/// Add `groups` to the start (if `prepend`) or end of the existing playlist. Groups for albums which
+/// are already in the playlist are skipped. Returns the IDs of the added tracks and of every track
+/// the playlist should now hold, in order.
+fn merge_into_playlist(client: &Client,
+                       playlist_id: &str,
+                       groups: Vec<TrackGroup>,
+                       prepend: bool)
+                       -> Result<(Vec<String>, Vec<String>)> {
+    let existing = client.playlist_tracks(playlist_id)?;
+    let existing_album_ids: HashSet<&String> =
+        existing.iter().filter_map(|t| t.track.album.id.as_ref()).collect();
+    let existing_track_ids: HashSet<&String> =
+        existing.iter().filter_map(|t| t.track.id.as_ref()).collect();
+
+    let (duplicates, mut groups): (Vec<TrackGroup>, Vec<TrackGroup>) = groups
+        .into_iter()
+        .partition(|g| g.album_id.as_ref().is_some_and(|id| existing_album_ids.contains(id)));
+    for group in duplicates.iter() {
+        info!(" = {} (already in playlist)", group.name);
+    }
+
+    // Groups which aren't albums can still share tracks with the playlist
+    let mut duplicate_tracks = 0usize;
+    for group in groups.iter_mut() {
+        let original_len = group.tracks.len();
+        group.tracks.retain(|t| !existing_track_ids.contains(&t.id));
+        duplicate_tracks += original_len - group.tracks.len();
+        group.duration = group.tracks.iter().map(|t| t.duration).sum();
+    }
+    groups.retain(|g| !g.tracks.is_empty());
+    if duplicate_tracks > 0 {
+        info!("Skipped {} tracks already in the playlist", duplicate_tracks);
+    }
+
+    let track_ids = group_track_ids(groups.as_slice());
+    client.add_tracks(playlist_id, track_ids.as_slice(), if prepend { Some(0) } else { None })?;
+
+    let existing_ids = existing.iter().filter_map(|t| t.track.id.clone());
+    let expected = if prepend {
+        track_ids.iter().cloned().chain(existing_ids).collect()
+    } else {
+        existing_ids.chain(track_ids.iter().cloned()).collect()
+    };
+    Ok((track_ids, expected))
+}
+
+/// Check if `a` and `b` contain the same track IDs, ignoring order.
+fn is_same_track_set(a: &[String], b: &[String]) -> bool {
+    let mut a = a.to_vec();
+    let mut b = b.to_vec();
+    a.sort();
+    b.sort();
+    a == b
+}
+
+/// Remove tracks from the playlist named `name` which are no longer in any source playlist (or saved
+/// album, with `include_saved_albums`) or which can't be played in the user's country.
+fn prune_shuffle(settings: &Settings, name: &str, include_saved_albums: bool) -> Result<()> {
+    let mut required_scopes = vec!["user-read-private",
+                                   "playlist-read-private",
+                                   "playlist-modify-private",
+                                   "playlist-modify-public"];
+    if include_saved_albums {
+        required_scopes.push("user-library-read");
+    }
+    let client = Client::new(settings, required_scopes.as_slice())?;
+
+    let playlists = client.current_user_playlists()?;
+    let target = playlists
+        .iter()
+        .find(|p| p.name.as_str() == name)
+        .ok_or_else(|| {
+            suggest_playlists(format!("No playlist named \"{}\"", name), name, playlists.as_slice())
+        })?;
+
+    let mut library_ids = HashSet::new();
+    let sources = playlists
+        .iter()
+        .filter(|p| p.id != target.id && !BANNED_PLAYLIST_NAMES.contains(&p.name.as_str()));
+    for playlist in sources {
+        let tracks = client.playlist_tracks(playlist.id.as_str())?;
+        library_ids.extend(tracks.into_iter().filter_map(|t| t.track.id));
+    }
+    if include_saved_albums {
+        for album in client.saved_albums()? {
+            library_ids.extend(album.tracks.items.into_iter().filter_map(|t| t.id));
+        }
+    }
+
+    let country = client.user_country()?;
+    let market = country.as_deref().and_then(Country::from_str);
+    if market.is_none() {
+        warn!("Account has no known country -- not checking whether tracks are available");
+    }
+
+    let mut stale_ids = Vec::new();
+    let mut pruned_json = Vec::new();
+    let mut seen_ids = HashSet::new();
+    for item in client.playlist_tracks_in_market(target.id.as_str(), market)? {
+        // Relinked tracks report the available release, but the playlist holds the original
+        let id = match (item.track.linked_from.as_ref(), item.track.id.as_ref()) {
+            (Some(link), _) => link.id.clone(),
+            (None, Some(id)) => id.clone(),
+            (None, None) => continue,
+        };
+        if !seen_ids.insert(id.clone()) {
+            continue
+        }
+
+        let reason = if !library_ids.contains(&id) {
+            "no longer in a source playlist".to_owned()
+        } else if item.track.is_playable == Some(false) {
+            format!("unavailable in {}", country.as_deref().unwrap_or_default())
+        } else {
+            continue
+        };
+        info!(" - {} ({})", item.track.name, reason);
+        pruned_json.push(serde_json::json!({ "id": id, "name": item.track.name, "reason": reason }));
+        stale_ids.push(id);
+    }
+
+    client.remove_tracks(target.id.as_str(), stale_ids.as_slice())?;
+    info!("Pruned {} tracks from {}", stale_ids.len(), target.name);
+    if settings.json {
+        println!("{}", serde_json::json!({ "playlist_id": target.id, "pruned": pruned_json }));
+    }
+    Ok(())
+}
+
+/// A snapshot of a single playlist, as written by `backup-all`.
+#[derive(Debug, Serialize, Deserialize)]
+struct PlaylistBackup {
+    pub id: String,
+    pub name: String,
+    pub snapshot_id: String,
+    pub track_ids: Vec<String>,
+    /// The extra track details requested with `--fields`, if any.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tracks: Vec<serde_json::Map<String, serde_json::Value>>,
+}
+
+/// Get the requested `fields` of the track as a JSON object, along with its ID.
+fn track_details(track: &FullTrack,
+                 fields: &[cmd::TrackField])
+                 -> serde_json::Map<String, serde_json::Value> {
+    use cmd::TrackField::*;
+    use serde_json::json;
+
+    let mut out = serde_json::Map::new();
+    out.insert("id".to_owned(), json!(track.id));
+    for &field in fields {
+        let value = match field {
+            Name => json!(track.name),
+            Artists => json!(track.artists.iter().map(|a| a.name.as_str()).collect::<Vec<_>>()),
+            Album => json!(track.album.name),
+            DurationMs => json!(track.duration_ms),
+            Isrc => json!(track.external_ids.get("isrc")),
+            Popularity => json!(track.popularity),
+            Explicit => json!(track.explicit),
+            TrackNumber => json!(track.track_number),
+        };
+        out.insert(field.as_str().to_owned(), value);
+    }
+    out
+}
+
+/// Write `playlist` to a JSON file in `dir`. Returns `false` if the backup was skipped because an
+/// existing backup file already has the same snapshot.
+fn backup_playlist(client: &Client,
+                   playlist: &SimplifiedPlaylist,
+                   dir: &Path,
+                   fields: &[cmd::TrackField])
+                   -> Result<bool> {
+    let path = dir.join(format!("{}.json", playlist.id));
+    let field_names: Vec<String> = fields.iter().map(|f| f.as_str().to_owned()).collect();
+
+    if let Ok(file) = File::open(&path) {
+        if let Ok(existing) = serde_json::from_reader::<_, PlaylistBackup>(BufReader::new(file)) {
+            if existing.snapshot_id == playlist.snapshot_id && existing.fields == field_names {
+                return Ok(false)
+            }
+        }
+    }
+
+    let tracks = client.playlist_tracks(playlist.id.as_str())?;
+    let backup = PlaylistBackup {
+        id: playlist.id.clone(),
+        name: playlist.name.clone(),
+        snapshot_id: playlist.snapshot_id.clone(),
+        track_ids: tracks.iter().filter_map(|t| t.track.id.clone()).collect(),
+        tracks: if fields.is_empty() {
+            Vec::new()
+        } else {
+            tracks.iter().map(|t| track_details(&t.track, fields)).collect()
+        },
+        fields: field_names,
+    };
+
+    // Write to a temporary file first so an interrupted backup never leaves a truncated file that
+    // looks complete
+    let tmp_path = path.with_extension("json.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    serde_json::to_writer_pretty(&mut writer, &backup)?;
+    // Dropping the writer would flush it too, but throw away any error, like a full disk
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(true)
+}
+
+fn backup_all(settings: &Settings, dir: &Path, jobs: usize, fields: &[cmd::TrackField]) -> Result<()> {
+    let client = Client::new(settings, &["playlist-read-private"])?;
+    fs::create_dir_all(dir)?;
+    let playlists = client.current_user_playlists()?;
+
+    let next_idx = AtomicUsize::new(0);
+    let written = AtomicUsize::new(0);
+    let skipped = AtomicUsize::new(0);
+    let failures = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            scope.spawn(|| {
+                while let Some(playlist) = playlists.get(next_idx.fetch_add(1, Ordering::SeqCst)) {
+                    match backup_playlist(&client, playlist, dir, fields) {
+                        Ok(true) => {
+                            info!(" + {}", playlist.name);
+                            written.fetch_add(1, Ordering::SeqCst);
+                        }
+                        Ok(false) => {
+                            skipped.fetch_add(1, Ordering::SeqCst);
+                        }
+                        Err(e) => {
+                            info!(" ! {}: {}", playlist.name, e);
+                            failures.lock().unwrap().push(playlist.name.clone());
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    let (written, skipped) = (written.into_inner(), skipped.into_inner());
+    let failures = failures.into_inner().unwrap();
+    info!("Backed up {} playlists, skipped {} unchanged", written, skipped);
+    if settings.json {
+        println!("{}", serde_json::json!({
+            "written": written,
+            "skipped": skipped,
+            "failed": failures,
+        }));
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Failed to back up {} playlists: {}", failures.len(), failures.join(", ")).into())
+    }
+}
+
+/// Print every playlist in the library with its ID and track count, marking the ones `shuffle-library`
+/// would not use as sources.
+fn list_playlists(settings: &Settings,
+                  sort: Option<cmd::PlaylistSort>,
+                  opts: &cmd::SourceOpts)
+                  -> Result<()> {
+    let client = Client::new(settings, &["playlist-read-private"])?;
+
+    let track_count = |p: &SimplifiedPlaylist| p.tracks.get("total").and_then(|t| t.as_u64()).unwrap_or(0);
+    let mut playlists = library_playlists(&client, opts)?;
+    match sort {
+        Some(cmd::PlaylistSort::Name) => playlists.sort_by_key(|p| p.name.to_lowercase()),
+        Some(cmd::PlaylistSort::Tracks) => playlists.sort_by_key(|p| std::cmp::Reverse(track_count(p))),
+        None => {}
+    }
+
+    if settings.json {
+        let playlists: Vec<serde_json::Value> = playlists
+            .iter()
+            .map(|p| serde_json::json!({
+                "id": p.id,
+                "name": p.name,
+                "tracks": track_count(p),
+                "excluded": exclusion_reason(p, opts),
+            }))
+            .collect();
+        println!("{}", serde_json::json!({ "playlists": playlists }));
+        return Ok(())
+    }
+
+    for playlist in playlists.iter() {
+        match exclusion_reason(playlist, opts) {
+            None => println!(" + {} {} ({} tracks)", playlist.id, playlist.name, track_count(playlist)),
+            Some(reason) => println!(" - {} {} ({} tracks; excluded: {})",
+                                     playlist.id,
+                                     playlist.name,
+                                     track_count(playlist),
+                                     reason),
+        }
+    }
+    Ok(())
+}
+
+/// Authenticate and make a single cheap API call, reporting the outcome. This exits the process with
+/// a non-zero code on failure.
+fn health(settings: &Settings, format: cmd::OutputFormat) -> Result<()> {
+    let result = Client::new(settings, &[])
+        .map_err(|e| ("auth", e))
+        .and_then(|client| {
+            let start = Instant::now();
+            client.call_api(|| client.native.current_user())
+                .map(|_| (client.user_id, start.elapsed()))
+                .map_err(|e| ("api", SpotctlError::from(e)))
+        });
+
+    let format = if settings.json { cmd::OutputFormat::Json } else { format };
+    match (result, format) {
+        (Ok((user_id, latency)), cmd::OutputFormat::Text) => {
+            println!("OK: authenticated as {} ({} ms)", user_id, latency.as_millis());
+            Ok(())
+        }
+        (Ok((user_id, latency)), cmd::OutputFormat::Json) => {
+            println!("{}", serde_json::json!({
+                "status": "ok",
+                "user_id": user_id,
+                "latency_ms": latency.as_secs_f64() * 1000.0,
+            }));
+            Ok(())
+        }
+        (Err((stage, e)), cmd::OutputFormat::Text) => {
+            println!("FAIL: {} error: {}", stage, e);
+            process::exit(e.exit_code())
+        }
+        (Err((stage, e)), cmd::OutputFormat::Json) => {
+            println!("{}", serde_json::json!({
+                "status": "error",
+                "stage": stage,
+                "reason": e.to_string(),
+            }));
+            process::exit(e.exit_code())
+        }
+    }
+}
+
+/// Send log messages to stderr: only warnings with `--quiet`, progress by default, debugging details
+/// with `-v` and everything, including from the HTTP client, with `-vv`. `RUST_LOG` overrides this.
+fn init_logging(verbose: u8, quiet: bool) {
+    let level = match (verbose, quiet) {
+        (0, true) => LevelFilter::Warn,
+        (0, false) => LevelFilter::Info,
+        (1, _) => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let others = match verbose {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Warn,
+        _ => LevelFilter::Trace,
+    };
+
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(others).filter_module("spotctl", level);
+    if let Ok(filters) = env::var("RUST_LOG") {
+        builder.parse_filters(filters.as_str());
+    }
+    builder
+        .format(move |buf, record| match (verbose, record.level()) {
+            (0, Level::Warn) => writeln!(buf, "Warning: {}", record.args()),
+            (0, _) => writeln!(buf, "{}", record.args()),
+            _ => writeln!(buf, "[{} {}] {}", record.level(), record.target(), record.args()),
+        })
+        .init();
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        process::exit(e.exit_code())
+    }
+}
+
+fn run() -> Result<()> {
+    use cmd::BaseCmd::*;
+
+    let opts = cmd::BaseOpts::from_args();
+    init_logging(opts.verbose, opts.quiet);
+    if let Completions { shell } = opts.command {
+        cmd::write_completions(shell, &mut io::stdout());
+        return Ok(())
+    }
+
+    let settings = Settings {
+        config_dir: config_dir(opts.config_dir.as_deref())?,
+        scope_debug: opts.scope_debug,
+        json: opts.json,
+        quiet: opts.quiet,
+        max_retries: opts.max_retries,
+        concurrency: opts.concurrency,
+        page_size: opts.page_size,
+    };
     match opts.command {
-        ShuffleLibrary => shuffle_library()
+        ShuffleLibrary(opts) => shuffle_library(&settings, &opts),
+        QueueNextAlbums { count, seed, device, opts } => {
+            queue_next_albums(&settings, count, seed, device.as_deref(), &opts)
+        }
+        ListPlaylists { sort, opts } => list_playlists(&settings, sort, &opts),
+        BackupAll { dir, jobs, fields } => backup_all(&settings, &dir, jobs, &fields),
+        PruneShuffle { playlist, include_saved_albums } =>
+            prune_shuffle(&settings, playlist.as_str(), include_saved_albums),
+        Restore { path, playlist } => restore_playlist(&settings, &path, playlist.as_str()),
+        Health { format } => health(&settings, format),
+        Completions { .. } => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A Spotify ID made of `c` repeated.
+    fn spotify_id(c: char) -> String {
+        c.to_string().repeat(22)
+    }
+
+    /// A playlist entry for a track of the album `album_id` which is `minutes` long.
+    fn playlist_track(track_id: Option<&str>, album_id: &str, minutes: u64) -> PlaylistTrack {
+        serde_json::from_value(serde_json::json!({
+            "added_at": "2020-01-01T00:00:00Z",
+            "added_by": null,
+            "is_local": track_id.is_none(),
+            "track": {
+                "album": {
+                    "album_type": "album",
+                    "artists": [],
+                    "external_urls": {},
+                    "href": null,
+                    "id": album_id,
+                    "images": [],
+                    "name": format!("Album {}", album_id),
+                    "type": "album",
+                    "uri": null,
+                },
+                "artists": [],
+                "disc_number": 1,
+                "duration_ms": minutes * 60 * 1000,
+                "explicit": false,
+                "external_ids": {},
+                "external_urls": {},
+                "href": null,
+                "id": track_id,
+                "is_local": track_id.is_none(),
+                "name": format!("Track {}", track_id.unwrap_or("local")),
+                "popularity": 0,
+                "preview_url": null,
+                "track_number": 1,
+                "type": "track",
+                "uri": "",
+            },
+        }))
+        .unwrap()
+    }
+
+    fn group_opts(args: &[&str]) -> cmd::GroupOpts {
+        cmd::GroupOpts::from_iter(std::iter::once("shuffle-library").chain(args.iter().cloned()))
+    }
+
+    #[test]
+    fn partition_by_album_drops_short_runs() {
+        let (a, b) = (spotify_id('a'), spotify_id('b'));
+        let tracks = vec![
+            playlist_track(Some("1"), &a, 5),
+            playlist_track(Some("2"), &a, 5),
+            playlist_track(Some("3"), &b, 11),
+        ];
+
+        // A run exactly as long as the minimum isn't an album
+        let groups = partition_by_album(&tracks, &AlbumAliases::default(), Duration::from_secs(600));
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].album_id.as_ref(), Some(&b));
+
+        let groups = partition_by_album(&tracks, &AlbumAliases::default(), Duration::from_secs(540));
+        assert_eq!(groups.len(), 2);
+    }
+
+    #[test]
+    fn partition_groups_min_album_length() {
+        let (a, b) = (spotify_id('a'), spotify_id('b'));
+        let tracks = vec![
+            playlist_track(Some("1"), &a, 4),
+            playlist_track(Some("2"), &a, 4),
+            playlist_track(Some("3"), &b, 20),
+        ];
+
+        let groups = partition_groups("Mix", &tracks, &AlbumAliases::default(), &group_opts(&[]));
+        assert_eq!(groups.len(), 1);
+
+        let opts = group_opts(&["--min-album-length", "5m"]);
+        let groups = partition_groups("Mix", &tracks, &AlbumAliases::default(), &opts);
+        assert_eq!(groups.len(), 2);
+    }
+
+    #[test]
+    fn partition_groups_min_session_length() {
+        let a = spotify_id('a');
+        let tracks = vec![playlist_track(Some("1"), &a, 6), playlist_track(Some("2"), &a, 6)];
+        let aliases = AlbumAliases::default();
+
+        let groups = partition_groups("Mix", &tracks, &aliases, &group_opts(&["--group-by", "session"]));
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].name, "Added 2020-01-01");
+
+        let opts = group_opts(&["--group-by", "session", "--min-album-length", "15m"]);
+        assert!(partition_groups("Mix", &tracks, &aliases, &opts).is_empty());
+    }
+
+    #[test]
+    fn partition_groups_playlist_as_album_thresholds() {
+        let tracks: Vec<PlaylistTrack> = ['a', 'b', 'c']
+            .iter()
+            .map(|&c| playlist_track(Some("1"), &spotify_id(c), 10))
+            .collect();
+        let aliases = AlbumAliases::default();
+
+        // 30 minutes is shorter than the default 45 minute minimum, so each album is its own group
+        let opts = group_opts(&["--playlist-as-album", "--min-album-length", "5m"]);
+        let groups = partition_groups("Mix", &tracks, &aliases, &opts);
+        assert_eq!(groups.len(), 3);
+
+        let opts = group_opts(&["--playlist-as-album", "--playlist-as-album-min", "20m"]);
+        let groups = partition_groups("Mix", &tracks, &aliases, &opts);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].name, "Mix");
+        assert_eq!(groups[0].album_id, None);
+        assert_eq!(groups[0].duration, Duration::from_secs(30 * 60));
+
+        let opts = group_opts(&["--playlist-as-album",
+                                "--playlist-as-album-min", "20m",
+                                "--playlist-as-album-max", "30m",
+                                "--min-album-length", "5m"]);
+        let groups = partition_groups("Mix", &tracks, &aliases, &opts);
+        assert_eq!(groups.len(), 3);
+    }
+
+    #[test]
+    fn partition_groups_skips_missing_tracks() {
+        let a = spotify_id('a');
+        let tracks = vec![
+            playlist_track(Some("1"), &a, 8),
+            playlist_track(None, &a, 8),
+            playlist_track(Some("2"), &a, 8),
+        ];
+
+        let groups = partition_groups("Mix", &tracks, &AlbumAliases::default(), &group_opts(&[]));
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].tracks.len(), 2);
+        assert_eq!(groups[0].duration, Duration::from_secs(16 * 60));
+    }
+
+    #[test]
+    fn dedupe_albums_keeps_first_group() {
+        let (a, b) = (spotify_id('a'), spotify_id('b'));
+        let aliases = AlbumAliases::from_sets(vec![vec![a.clone(), b.clone()]]).unwrap();
+        let min = Duration::from_secs(600);
+        let first = partition_by_album(&[playlist_track(Some("1"), &a, 11)], &aliases, min);
+        let second = partition_by_album(&[playlist_track(Some("2"), &b, 12)], &aliases, min);
+        let mut groups: Vec<TrackGroup> = first.into_iter().chain(second).collect();
+        let mut session = TrackGroup::from(&[playlist_track(Some("3"), &a, 13)][..]);
+        session.album_id = None;
+        groups.push(session);
+
+        assert_eq!(dedupe_albums(&mut groups), 1);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].tracks[0].id, "1");
+        assert_eq!(groups[1].album_id, None);
+    }
+
+    /// One album track group per letter of `albums`, each a single track `minutes` long.
+    fn album_groups(albums: &str, minutes: u64) -> Vec<TrackGroup> {
+        albums
+            .chars()
+            .map(|c| {
+                let album_id = spotify_id(c);
+                TrackGroup::from(&[playlist_track(Some(c.to_string().as_str()), &album_id, minutes)][..])
+            })
+            .collect()
+    }
+
+    fn shuffle_library_opts(args: &[&str]) -> cmd::ShuffleLibraryOpts {
+        cmd::ShuffleLibraryOpts::from_iter(std::iter::once("shuffle-library").chain(args.iter().cloned()))
+    }
+
+    fn group_names(groups: &[TrackGroup]) -> Vec<String> {
+        groups.iter().map(|g| g.name.clone()).collect()
+    }
+
+    #[test]
+    fn create_playlist_same_seed_same_order() {
+        let opts = shuffle_library_opts(&[]);
+        let history = HashMap::new();
+        let shuffle = |seed| {
+            let mut rng = shuffle_rng(Some(seed));
+            let (groups, _) = create_playlist(album_groups("abcdefghij", 11), None, &mut rng, &history, &opts)
+                .unwrap();
+            group_names(groups.as_slice())
+        };
+
+        assert_eq!(shuffle(42), shuffle(42));
+        assert_eq!(shuffle(42).len(), 10);
+    }
+
+    #[test]
+    fn create_playlist_fills_target_duration() {
+        let opts = shuffle_library_opts(&["--order", "album"]);
+        let history = HashMap::new();
+        let mut rng = shuffle_rng(Some(0));
+
+        // Groups are added until the shuffle is longer than the goal, so the last one goes past it
+        let goal = Some(Duration::from_secs(30 * 60));
+        let (groups, cut_off) =
+            create_playlist(album_groups("abcdefghij", 11), goal, &mut rng, &history, &opts).unwrap();
+        assert_eq!(group_names(groups.as_slice()), vec!["Album aaaaaaaaaaaaaaaaaaaaaa",
+                                                         "Album bbbbbbbbbbbbbbbbbbbbbb",
+                                                         "Album cccccccccccccccccccccc"]);
+        assert_eq!(cut_off, 7);
+
+        // Reaching the goal exactly isn't enough to stop
+        let goal = Some(Duration::from_secs(33 * 60));
+        let (groups, cut_off) =
+            create_playlist(album_groups("abcdefghij", 11), goal, &mut rng, &history, &opts).unwrap();
+        assert_eq!(groups.len(), 4);
+        assert_eq!(cut_off, 6);
+
+        // Running out of groups before the goal keeps all of them
+        let (groups, cut_off) =
+            create_playlist(album_groups("abc", 11), goal, &mut rng, &history, &opts).unwrap();
+        assert_eq!(groups.len(), 3);
+        assert_eq!(cut_off, 0);
+    }
+
+    #[test]
+    fn retry_delay_doubles() {
+        assert_eq!(retry_delay(0, None), Duration::from_secs(1));
+        assert_eq!(retry_delay(1, None), Duration::from_secs(2));
+        assert_eq!(retry_delay(2, None), Duration::from_secs(4));
+        assert_eq!(retry_delay(5, None), Duration::from_secs(32));
+    }
+
+    #[test]
+    fn retry_delay_is_capped() {
+        assert_eq!(retry_delay(6, None), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(7, None), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(63, None), MAX_RETRY_DELAY);
+        // Shifting by 64 or more overflows
+        assert_eq!(retry_delay(64, None), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(u32::MAX, None), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn retry_delay_prefers_server_timeout() {
+        assert_eq!(retry_delay(0, Some(30)), Duration::from_secs(30));
+        assert_eq!(retry_delay(10, Some(3)), Duration::from_secs(3));
+        assert_eq!(retry_delay(3, Some(120)), Duration::from_secs(120));
+    }
+
+    #[test]
+    fn album_aliases_map_to_first_id() {
+        let (a, b, c) = (spotify_id('a'), spotify_id('b'), spotify_id('c'));
+        let aliases = AlbumAliases::from_sets(vec![vec![a.clone(), format!("spotify:album:{}", b)]]).unwrap();
+        assert_eq!(aliases.canonical(&a), a);
+        assert_eq!(aliases.canonical(&b), a);
+        assert_eq!(aliases.canonical(&c), c);
+    }
+
+    #[test]
+    fn album_aliases_reject_id_in_two_sets() {
+        let (a, b, c) = (spotify_id('a'), spotify_id('b'), spotify_id('c'));
+        assert!(AlbumAliases::from_sets(vec![vec![a, b.clone()], vec![c, b]]).is_err());
+    }
+
+    #[test]
+    fn album_aliases_ignore_empty_sets() {
+        let a = spotify_id('a');
+        let aliases = AlbumAliases::from_sets(vec![vec![], vec![]]).unwrap();
+        assert!(aliases.canonical.is_empty());
+        assert_eq!(aliases.canonical(&a), a);
+    }
+
+    #[test]
+    fn partition_by_album_merges_aliased_runs() {
+        let (a, b, c) = (spotify_id('a'), spotify_id('b'), spotify_id('c'));
+        let aliases = AlbumAliases::from_sets(vec![vec![a.clone(), b.clone()]]).unwrap();
+        let tracks = vec![
+            playlist_track(Some("1"), &a, 6),
+            playlist_track(Some("2"), &b, 6),
+            playlist_track(Some("3"), &c, 11),
+        ];
+
+        let groups = partition_by_album(&tracks, &aliases, Duration::from_secs(600));
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].album_id.as_ref(), Some(&a));
+        assert_eq!(groups[0].tracks.len(), 2);
+        assert_eq!(groups[0].duration, Duration::from_secs(12 * 60));
+        assert_eq!(groups[1].album_id.as_ref(), Some(&c));
+
+        // Without the alias, neither half is long enough to count as an album
+        let groups = partition_by_album(&tracks, &AlbumAliases::default(), Duration::from_secs(600));
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].album_id.as_ref(), Some(&c));
     }
 }